@@ -0,0 +1,120 @@
+//! Binary encoding used by `SplitOrderedList::checkpoint` and `SplitOrderedList::restore`.
+//!
+//! A checkpoint is the number of entries followed by the entries, each a key and a length-prefixed
+//! value. Numbers are little-endian `u64`s.
+
+use core::convert::TryInto;
+use core::mem;
+
+/// Values that can be stored in a checkpoint.
+pub trait Encode: Sized {
+    /// Appends the encoding of `self` to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a value from exactly the bytes produced by `encode`.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_encode_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(bytes: &[u8]) -> Option<Self> {
+                    Some(<$t>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_encode_for_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl Encode for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+/// Error returned by `SplitOrderedList::restore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The buffer ended in the middle of the checkpoint.
+    Truncated,
+    /// The buffer continues past the last entry.
+    TrailingBytes,
+    /// A key is outside the range accepted by the map.
+    InvalidKey(u64),
+    /// A key appears more than once.
+    DuplicateKey(usize),
+    /// The value stored for the key could not be decoded.
+    InvalidValue(usize),
+}
+
+pub(crate) fn write_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Reads a `u64` from the front of `bytes` and advances it.
+pub(crate) fn read_u64(bytes: &mut &[u8]) -> Result<u64, RestoreError> {
+    let n = read_bytes(bytes, mem::size_of::<u64>())?;
+    Ok(u64::from_le_bytes(n.try_into().unwrap()))
+}
+
+/// Reads `len` bytes from the front of `bytes` and advances it.
+pub(crate) fn read_bytes<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], RestoreError> {
+    if bytes.len() < len {
+        return Err(RestoreError::Truncated);
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let mut buf = Vec::new();
+        (-5i32).encode(&mut buf);
+        assert_eq!(i32::decode(&buf), Some(-5));
+        assert_eq!(u64::decode(&buf), None);
+
+        buf.clear();
+        "owl".to_string().encode(&mut buf);
+        assert_eq!(String::decode(&buf).as_deref(), Some("owl"));
+        assert_eq!(String::decode(&[0xff]), None);
+    }
+
+    #[test]
+    fn reads_advance_and_stop_at_the_end() {
+        let mut buf = Vec::new();
+        write_u64(&mut buf, 7);
+        buf.extend_from_slice(b"ab");
+        let mut bytes = &buf[..];
+        assert_eq!(read_u64(&mut bytes), Ok(7));
+        assert_eq!(read_bytes(&mut bytes, 3), Err(RestoreError::Truncated));
+        assert_eq!(read_bytes(&mut bytes, 2), Ok(&b"ab"[..]));
+        assert!(bytes.is_empty());
+        assert_eq!(read_u64(&mut bytes), Err(RestoreError::Truncated));
+    }
+}
@@ -0,0 +1,109 @@
+//! Striped counter used for the number of entries of `SplitOrderedList`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_utils::CachePadded;
+
+/// Number of stripes. Threads beyond that share stripes.
+const STRIPES: usize = 16;
+
+/// Source of the stripe index handed out to each thread.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES;
+}
+
+/// Counter split into cache-padded stripes, so that threads updating it concurrently don't contend
+/// on a single cache line.
+///
+/// Each thread updates its own stripe and reads sum all of them. A stripe may wrap below zero when
+/// a thread decrements what another one incremented, but since all arithmetic wraps, the sum is
+/// still exact.
+#[derive(Debug, Default)]
+pub(crate) struct StripedCounter {
+    stripes: [CachePadded<AtomicUsize>; STRIPES],
+}
+
+impl StripedCounter {
+    fn stripe(&self) -> &AtomicUsize {
+        &self.stripes[STRIPE.with(|stripe| *stripe)]
+    }
+
+    pub(crate) fn increment(&self) {
+        self.stripe().fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn decrement(&self) {
+        self.stripe().fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Estimates the sum from the stripe of the calling thread alone, assuming that every stripe
+    /// handed out so far holds about as much. It only reads one cache line, unlike `sum`, but is
+    /// off by however unevenly the threads update the counter.
+    pub(crate) fn estimate(&self) -> usize {
+        let stripes = NEXT_STRIPE.load(Ordering::Relaxed).clamp(1, STRIPES);
+        let stripe = self.stripe().load(Ordering::Acquire);
+        // A stripe that wrapped below zero holds less than the others
+        if stripe > isize::MAX as usize {
+            0
+        } else {
+            stripe.saturating_mul(stripes)
+        }
+    }
+
+    /// Sums the stripes. Updates made while summing may or may not be included.
+    pub(crate) fn sum(&self) -> usize {
+        self.stripes.iter().fold(0, |sum, stripe| {
+            sum.wrapping_add(stripe.load(Ordering::Acquire))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn sum_is_exact_across_threads() {
+        let counter = StripedCounter::default();
+        thread::scope(|s| {
+            for t in 0..8 {
+                let counter = &counter;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        counter.increment();
+                    }
+                    // Half the threads take back what the other half added
+                    if t % 2 == 0 {
+                        for _ in 0..2000 {
+                            counter.decrement();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.sum(), 0);
+    }
+
+    #[test]
+    fn estimate_scales_the_own_stripe() {
+        let counter = StripedCounter::default();
+        for _ in 0..100 {
+            counter.increment();
+        }
+        // Only this thread's stripe is in use, and it may be scaled by more stripes
+        assert!(counter.estimate() >= counter.sum());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    counter.decrement();
+                }
+                // The stripe of this thread wrapped below zero
+                assert_eq!(counter.estimate(), 0);
+            });
+        });
+        assert_eq!(counter.sum(), 0);
+    }
+}
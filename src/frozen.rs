@@ -0,0 +1,57 @@
+//! Immutable map produced by `SplitOrderedList::freeze`.
+
+/// Read-only map backed by a sorted array of entries.
+///
+/// Lookups are a binary search with no epoch pinning and no atomic operations. Since the map is
+/// immutable, it is `Sync` whenever `V` is, so any number of threads can read it concurrently.
+#[derive(Debug, Clone)]
+pub struct FrozenMap<V> {
+    /// Entries sorted by key, without duplicate keys.
+    entries: Vec<(usize, V)>,
+}
+
+impl<V> FrozenMap<V> {
+    /// Creates a map from entries sorted by key, without duplicate keys.
+    pub(crate) fn from_sorted(entries: Vec<(usize, V)>) -> Self {
+        debug_assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        Self { entries }
+    }
+
+    /// Returns the value for `key`.
+    pub fn get(&self, key: &usize) -> Option<&V> {
+        self.entries
+            .binary_search_by_key(key, |(k, _)| *k)
+            .ok()
+            .map(|i| &self.entries[i].1)
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_finds_exactly_the_stored_keys() {
+        let map = FrozenMap::from_sorted((0..50).map(|key| (key * 3, key)).collect());
+        assert_eq!(map.len(), 50);
+        assert!(!map.is_empty());
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&147), Some(&49));
+        assert_eq!(map.get(&4), None);
+        assert_eq!(map.get(&150), None);
+
+        let empty = FrozenMap::<()>::from_sorted(Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.get(&0), None);
+    }
+}
@@ -1,9 +1,13 @@
 use core::fmt::Debug;
 use core::marker::PhantomData;
 use core::mem;
-use core::ops::{Deref, DerefMut};
+use core::slice;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crossbeam_epoch::{unprotected, Atomic, Guard, Owned, Pointer, Shared};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::sync::Arc;
 
 /// Growable array of `Atomic<T>`.
 ///
@@ -127,52 +131,181 @@ use crossbeam_epoch::{unprotected, Atomic, Guard, Owned, Pointer, Shared};
 /// Instead, it should be handled by the container that the elements actually belong to. For
 /// example in `SplitOrderedList`, destruction of elements are handled by `List`.
 ///
+/// Segments hold `1 << SEGMENT_LOGSIZE` slots, 1024 by default. A smaller size wastes less memory
+/// on small or sparse arrays at the cost of a taller tree. `SEGMENT_LOGSIZE` must be at least 2,
+/// which keeps the height of the tree within the tag bits of a segment pointer.
+///
+/// Segments are allocated from and returned to `A`, which defaults to the global allocator.
+///
+/// # Pointer stability
+///
+/// Growing the tree only adds segments: a new root is put above the old one, and new child
+/// segments are put into null slots. An existing segment is never moved or freed before the array
+/// is dropped, so a reference returned by `get` keeps pointing to the same slot for the lifetime of
+/// the array, however much the tree grows afterwards. `SplitOrderedList` relies on this to store
+/// the slot of a bucket as the `prev` pointer of its cursors. The only exceptions are
+/// `replace_subtree` and `compact_concurrent`, which are `unsafe` for that reason.
+///
 #[derive(Debug)]
-pub struct GrowableArray<T> {
-    root: Atomic<Segment>,
+pub struct GrowableArray<T, const SEGMENT_LOGSIZE: usize = 10, A: GlobalAlloc = Global> {
+    root: Atomic<Segment<SEGMENT_LOGSIZE>>,
+    alloc: A,
+    /// Freed segments kept for reuse, set by `with_segment_pool`
+    pool: Option<Arc<SegmentPool<SEGMENT_LOGSIZE>>>,
     _marker: PhantomData<T>,
 }
 
-const SEGMENT_LOGSIZE: usize = 10;
+/// The global allocator, i.e. the one registered with `#[global_allocator]` if any.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+}
+
+/// Error returned when a segment could not be allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
 
-struct Segment {
+/// Segment of `1 << SEGMENT_LOGSIZE` slots.
+///
+/// Stable Rust can't use `1 << SEGMENT_LOGSIZE` as an array length, so the type only marks the
+/// start of the slots, which are allocated right behind it with `Segment::layout`. The alignment
+/// leaves 6 tag bits for the height of the segment.
+///
+/// Being zero-sized, a `&Segment` is only valid for 0 bytes, and dropping a `Box<Segment>` or an
+/// `Owned<Segment>` frees nothing. So segments are only handled through `Shared` pointers: their
+/// slots are reached with `Segment::slots` from the pointer to the allocation, and they are freed
+/// with `free_segment`.
+#[repr(C, align(64))]
+struct Segment<const SEGMENT_LOGSIZE: usize> {
     /// `AtomicUsize` here means `Atomic<T>` or `Atomic<Segment>`.
-    inner: [AtomicUsize; 1 << SEGMENT_LOGSIZE],
+    inner: [AtomicUsize; 0],
 }
 
-impl Segment {
-    fn new() -> Self {
-        Self {
-            inner: unsafe { mem::zeroed() },
-        }
+impl<const SEGMENT_LOGSIZE: usize> Segment<SEGMENT_LOGSIZE> {
+    fn layout() -> Layout {
+        let size = mem::size_of::<AtomicUsize>() << SEGMENT_LOGSIZE;
+        Layout::from_size_align(size, mem::align_of::<Self>()).unwrap()
+    }
+
+    /// Returns the slots of the segment `segment` points to.
+    ///
+    /// # Safety
+    ///
+    /// `segment` must point to a segment that stays allocated for `'g`.
+    unsafe fn slots<'g>(segment: Shared<'g, Self>) -> &'g [AtomicUsize] {
+        slice::from_raw_parts(segment.as_raw() as *const AtomicUsize, 1 << SEGMENT_LOGSIZE)
     }
 
-    fn get_unchecked(&self, index: usize) -> &AtomicUsize {
-        return &self.inner[index];
+    /// Returns the slot at `index` of `segment` as the pointer to a child segment.
+    ///
+    /// # Safety
+    ///
+    /// Same as `slots`.
+    unsafe fn child<'g>(segment: Shared<'g, Self>, index: usize) -> &'g Atomic<Self> {
+        &*(&Self::slots(segment)[index] as *const _ as *const Atomic<Self>)
+    }
+}
+
+impl<const SEGMENT_LOGSIZE: usize> Debug for Segment<SEGMENT_LOGSIZE> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Segment")
     }
 }
 
-impl Deref for Segment {
-    type Target = [AtomicUsize; 1 << SEGMENT_LOGSIZE];
+/// Bounded pool of free segments, shared by an array and the subtrees it retires.
+///
+/// Each slot holds at most one segment. Taking a segment swaps its slot to null, so a segment is
+/// handed out exactly once without the ABA problem of a linked free list. Only segments no other
+/// thread can reach are put back, and they are zeroed again when taken. Segments in the pool were
+/// allocated from `Global`, which they are returned to when the pool is dropped.
+#[derive(Debug)]
+struct SegmentPool<const SEGMENT_LOGSIZE: usize> {
+    slots: Box<[Atomic<Segment<SEGMENT_LOGSIZE>>]>,
+}
+
+impl<const SEGMENT_LOGSIZE: usize> SegmentPool<SEGMENT_LOGSIZE> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| Atomic::null()).collect(),
+        }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    fn pop(&self) -> Option<Shared<'static, Segment<SEGMENT_LOGSIZE>>> {
+        let guard = unsafe { unprotected() };
+        for slot in self.slots.iter() {
+            // Only write to slots worth taking, so popping from a mostly empty pool doesn't pull
+            // every slot's cache line in exclusive mode
+            if slot.load(Ordering::Relaxed, guard).is_null() {
+                continue;
+            }
+            let segment = slot.swap(Shared::null(), Ordering::Acquire, guard);
+            if !segment.is_null() {
+                for slot in unsafe { Segment::slots(segment) } {
+                    slot.store(0, Ordering::Relaxed);
+                }
+                return Some(segment);
+            }
+        }
+        None
+    }
+
+    /// Returns false if the pool is full, in which case the segment is left to the caller.
+    fn push(&self, segment: Shared<'_, Segment<SEGMENT_LOGSIZE>>) -> bool {
+        let guard = unsafe { unprotected() };
+        let segment = segment.with_tag(0);
+        for slot in self.slots.iter() {
+            if slot
+                .compare_and_set(Shared::null(), segment, Ordering::Release, guard)
+                .is_ok()
+            {
+                return true;
+            }
+        }
+        false
     }
 }
 
-impl DerefMut for Segment {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+impl<const SEGMENT_LOGSIZE: usize> Drop for SegmentPool<SEGMENT_LOGSIZE> {
+    fn drop(&mut self) {
+        while let Some(segment) = self.pop() {
+            let ptr = segment.as_raw() as *mut u8;
+            unsafe { Global.dealloc(ptr, Segment::<SEGMENT_LOGSIZE>::layout()) };
+        }
     }
 }
 
-impl Debug for Segment {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Segment")
+/// Hints the CPU to start loading the cache line at `p`. Compiles to nothing without the
+/// `prefetch` feature or on targets other than x86 and x86_64. Never faults, even on a bad address.
+#[inline(always)]
+fn prefetch<P>(p: *const P) {
+    #[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+    unsafe {
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch(p as *const i8, _MM_HINT_T0);
     }
+    #[cfg(all(feature = "prefetch", target_arch = "x86"))]
+    unsafe {
+        use core::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch(p as *const i8, _MM_HINT_T0);
+    }
+    let _ = p;
 }
 
-impl<T> Drop for GrowableArray<T> {
+impl<T, const SEGMENT_LOGSIZE: usize, A: GlobalAlloc> Drop
+    for GrowableArray<T, SEGMENT_LOGSIZE, A>
+{
     /// Deallocate segments, but not the individual elements.
     fn drop(&mut self) {
         unsafe {
@@ -183,45 +316,273 @@ impl<T> Drop for GrowableArray<T> {
                 return;
             }
 
-            let root_height = root.tag();
-            let owned = root.into_owned();
-            self._drop(owned, root_height, guard);
+            self._drop(root, root.tag());
         }
     }
 }
 
-impl<T> Default for GrowableArray<T> {
+impl<T, const SEGMENT_LOGSIZE: usize> Default for GrowableArray<T, SEGMENT_LOGSIZE> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T> GrowableArray<T> {
+impl<T, const SEGMENT_LOGSIZE: usize> GrowableArray<T, SEGMENT_LOGSIZE> {
     /// Create a new growable array.
     pub fn new() -> Self {
+        Self::with_allocator(Global)
+    }
+
+    /// Creates a growable array that keeps up to `capacity` freed segments for reuse instead of
+    /// returning them to the allocator right away, for workloads that repeatedly grow and compact
+    /// the tree.
+    ///
+    /// The pool is shared with the subtrees retired by `compact_concurrent` and `replace_subtree`
+    /// and with arrays split off by `split_off`, which give their segments back to it.
+    pub fn with_segment_pool(capacity: usize) -> Self {
+        let mut array = Self::new();
+        array.pool = Some(Arc::new(SegmentPool::new(capacity)));
+        array
+    }
+
+    /// Create a new growable array whose root already has height `height`, i.e. with segments
+    /// allocated along the path to index 0. Returns `AllocError` instead of aborting if a segment
+    /// can't be allocated, in which case the segments allocated so far are freed.
+    pub fn try_with_initial_height(height: usize) -> Result<Self, AllocError> {
+        Self::try_with_initial_height_in(height, Global)
+    }
+
+    /// Replaces the segment of height `level` that holds indices `prefix..prefix + (1 <<
+    /// (level * SEGMENT_LOGSIZE))` by the root segment of `new_subtree`, whose index `i` ends up at
+    /// index `prefix + i`. `prefix` must be a multiple of `1 << (level * SEGMENT_LOGSIZE)`, and
+    /// `new_subtree` must not hold indices past the end of that range.
+    ///
+    /// The old segments are freed once no thread pinned before the swap can still read them, but
+    /// their elements are not. Stores racing with the swap may land in the old segments and be
+    /// lost.
+    ///
+    /// # Safety
+    ///
+    /// `get` hands out references that live as long as the array, not as long as the guard. The
+    /// caller must guarantee that no reference obtained from `get` for an index in the replaced
+    /// range is used once the guards pinned at the time of the call are dropped.
+    pub unsafe fn replace_subtree(
+        &self,
+        prefix: usize,
+        level: usize,
+        new_subtree: GrowableArray<T, SEGMENT_LOGSIZE>,
+        guard: &Guard,
+    ) {
+        let span = level * SEGMENT_LOGSIZE;
+        assert!(level >= 1 && span < mem::size_of::<usize>() * 8);
+        assert!(prefix & ((1 << span) - 1) == 0, "unaligned subtree prefix");
+
+        // Bring the new subtree to exactly LEVEL, so that it can hang below a segment of height
+        // LEVEL + 1
+        let new_root = new_subtree.root.load(Ordering::Acquire, guard);
+        assert!(
+            new_root.tag() <= level,
+            "subtree holds indices out of range"
+        );
+        if !new_root.is_null() {
+            new_subtree.ensure_root_height(level, &mut false, guard);
+        }
+        let new_root = new_subtree
+            .root
+            .swap(Shared::null(), Ordering::AcqRel, guard);
+
+        // Make sure the segment of height LEVEL + 1 above the replaced one exists
+        let mut installed = false;
+        self.get_slot(prefix, &mut installed, guard);
+        self.ensure_root_height(level + 1, &mut installed, guard);
+
+        let slot = self.get_segment_slot(prefix, level, guard);
+        let old = slot.swap(new_root, Ordering::AcqRel, guard);
+        if !old.is_null() {
+            let old = GrowableArray::<T, SEGMENT_LOGSIZE> {
+                root: Atomic::from(old),
+                alloc: Global,
+                pool: self.pool.clone(),
+                _marker: PhantomData,
+            };
+            guard.defer_unchecked(move || drop(old));
+        }
+    }
+
+    /// Detaches segments below the root that hold only null pointers, and frees them once no
+    /// thread pinned before the detach can still read them. Returns the number of segments
+    /// detached. Runs concurrently with readers and writers.
+    ///
+    /// A segment is scanned, detached with a CAS, and scanned again. If it was populated in
+    /// between, it is put back. This is best effort: a store into a slot obtained from `get`
+    /// before the segment was detached, but made after the second scan, lands in the detached
+    /// segment and is lost.
+    ///
+    /// # Safety
+    ///
+    /// `get` hands out references that live as long as the array, not as long as the guard. The
+    /// caller must guarantee that no reference obtained from `get` is used once the guard it was
+    /// obtained with is dropped, as the segment holding it may have been detached and freed.
+    pub unsafe fn compact_concurrent(&self, guard: &Guard) -> usize {
+        let root = self.root.load(Ordering::Acquire, guard);
+        if root.tag() < 2 {
+            return 0;
+        }
+        self._compact(root, root.tag(), guard)
+    }
+
+    unsafe fn _compact<'g>(
+        &self,
+        segment: Shared<'g, Segment<SEGMENT_LOGSIZE>>,
+        height: usize,
+        guard: &'g Guard,
+    ) -> usize {
+        // Detaches the empty children of SEGMENT by DFS traversal, children first so that a
+        // segment whose children were all detached can go as well
+        // HEIGHT is the height of SEGMENT, at least 2
+
+        let mut detached = 0;
+        for i in 0..(1 << SEGMENT_LOGSIZE) {
+            let slot = Segment::child(segment, i);
+            let child = slot.load(Ordering::Acquire, guard);
+            if child.is_null() {
+                continue;
+            }
+            if height > 2 {
+                detached += self._compact(child, height - 1, guard);
+            }
+
+            let is_empty = |child: Shared<'_, Segment<SEGMENT_LOGSIZE>>| {
+                Segment::slots(child)
+                    .iter()
+                    .all(|slot| slot.load(Ordering::Acquire) == 0)
+            };
+            if !is_empty(child) {
+                continue;
+            }
+            if slot
+                .compare_and_set(child, Shared::null(), Ordering::AcqRel, guard)
+                .is_err()
+            {
+                continue;
+            }
+            // Back off if the child was populated between the scan and the detach. If a fresh
+            // segment already took its place, the entries stored in the child are lost
+            if !is_empty(child)
+                && slot
+                    .compare_and_set(Shared::null(), child, Ordering::AcqRel, guard)
+                    .is_ok()
+            {
+                continue;
+            }
+
+            let child = GrowableArray::<T, SEGMENT_LOGSIZE> {
+                root: Atomic::from(child),
+                alloc: Global,
+                pool: self.pool.clone(),
+                _marker: PhantomData,
+            };
+            guard.defer_unchecked(move || drop(child));
+            detached += 1;
+        }
+        detached
+    }
+}
+
+impl<T, const SEGMENT_LOGSIZE: usize, A: GlobalAlloc> GrowableArray<T, SEGMENT_LOGSIZE, A> {
+    /// Create a new growable array whose segments are allocated from `alloc`.
+    pub fn with_allocator(alloc: A) -> Self {
+        assert!(2 <= SEGMENT_LOGSIZE && SEGMENT_LOGSIZE < mem::size_of::<usize>() * 8);
         Self {
             root: Atomic::null(),
+            alloc,
+            pool: None,
             _marker: PhantomData,
         }
     }
 
-    fn _drop(&mut self, owned: Owned<Segment>, height: usize, guard: &Guard) {
-        // Drop segments by DFS traversal
+    /// Like `try_with_initial_height`, but the segments are allocated from `alloc`.
+    pub fn try_with_initial_height_in(height: usize, alloc: A) -> Result<Self, AllocError> {
+        let max_height = (mem::size_of::<usize>() * 8).div_ceil(SEGMENT_LOGSIZE);
+        assert!(height <= max_height);
 
-        if height == 1 {
-            drop(owned);
-            return;
+        let array = Self::with_allocator(alloc);
+        unsafe {
+            let guard = unprotected();
+            for new_root_height in 1..=height {
+                let new_root = match array.try_alloc_segment() {
+                    Some(segment) => segment,
+                    // Dropping `array` frees the segments installed so far.
+                    None => return Err(AllocError),
+                };
+                let root = array.root.load(Ordering::Relaxed, guard);
+                Segment::slots(new_root)[0].store(root.into_usize(), Ordering::Relaxed);
+                array
+                    .root
+                    .store(new_root.with_tag(new_root_height), Ordering::Relaxed);
+            }
         }
+        Ok(array)
+    }
 
-        for i in 0..(1 << SEGMENT_LOGSIZE) {
-            let child =
-                unsafe { &*((*owned).get_unchecked(i) as *const _ as *const Atomic<Segment>) };
-            let temp = (*child).load(Ordering::Acquire, guard);
-            if !temp.is_null() {
-                self._drop(unsafe { temp.into_owned() }, height - 1, guard);
+    fn try_alloc_segment(&self) -> Option<Shared<'static, Segment<SEGMENT_LOGSIZE>>> {
+        // Zeroed memory is a valid `Segment`
+        // The segment must be given back through free_segment
+
+        if let Some(segment) = self.pool.as_ref().and_then(|pool| pool.pop()) {
+            return Some(segment);
+        }
+
+        let ptr = unsafe {
+            self.alloc
+                .alloc_zeroed(Segment::<SEGMENT_LOGSIZE>::layout())
+                as *mut Segment<SEGMENT_LOGSIZE>
+        };
+        if ptr.is_null() {
+            return None;
+        }
+        Some(Shared::from(ptr as *const Segment<SEGMENT_LOGSIZE>))
+    }
+
+    fn alloc_segment(&self) -> Shared<'static, Segment<SEGMENT_LOGSIZE>> {
+        match self.try_alloc_segment() {
+            Some(segment) => segment,
+            None => alloc::handle_alloc_error(Segment::<SEGMENT_LOGSIZE>::layout()),
+        }
+    }
+
+    unsafe fn free_segment(&self, segment: Shared<'_, Segment<SEGMENT_LOGSIZE>>) {
+        // SEGMENT must have been allocated by try_alloc_segment, and must not be reachable by
+        // other threads anymore
+        if let Some(pool) = self.pool.as_ref() {
+            if pool.push(segment) {
+                return;
+            }
+        }
+
+        self.alloc.dealloc(
+            segment.as_raw() as *mut u8,
+            Segment::<SEGMENT_LOGSIZE>::layout(),
+        );
+    }
+
+    unsafe fn _drop(&mut self, root: Shared<'_, Segment<SEGMENT_LOGSIZE>>, height: usize) {
+        // Drop segments by DFS traversal, keeping the segments still to visit on an explicit
+        // stack rather than recursing
+
+        let guard = unprotected();
+        let mut stack = vec![(root, height)];
+        while let Some((segment, height)) = stack.pop() {
+            if height > 1 {
+                for i in 0..(1 << SEGMENT_LOGSIZE) {
+                    let child = Segment::child(segment, i).load(Ordering::Acquire, guard);
+                    if !child.is_null() {
+                        stack.push((child, height - 1));
+                    }
+                }
             }
+            self.free_segment(segment);
         }
-        drop(owned);
     }
 
     fn get_bits_at(&self, index: usize, mut mask: usize, at: usize) -> usize {
@@ -230,100 +591,1257 @@ impl<T> GrowableArray<T> {
         // Ex: at = 0 returns SEGMENT_LOGSIZE lsb of INDEX
         // Ex: at = 1 return next SEGMENT_LOGSIZE lsb of INDEX
 
-        mask = mask << (at * SEGMENT_LOGSIZE);
+        mask <<= at * SEGMENT_LOGSIZE;
         let mut bits: usize = index & mask;
-        bits = bits >> (at * SEGMENT_LOGSIZE);
-        return bits;
+        bits >>= at * SEGMENT_LOGSIZE;
+        bits
     }
 
     fn get_msb_index(&self, index: usize) -> usize {
         let zeros = index.leading_zeros() as usize;
         let size = mem::size_of::<usize>() * 8;
-        return size - zeros;
+        size - zeros
     }
 
-    fn ensure_root_height(&self, height: usize, guard: &Guard) {
+    fn get_required_height(&self, index: usize) -> usize {
+        // Height the root must have for INDEX to be addressable
+
+        let msb = self.get_msb_index(index);
+        if msb.is_multiple_of(SEGMENT_LOGSIZE) {
+            msb / SEGMENT_LOGSIZE
+        } else {
+            msb / SEGMENT_LOGSIZE + 1
+        }
+    }
+
+    fn ensure_root_height(&self, height: usize, installed: &mut bool, guard: &Guard) {
         // Ensures that root of GrowableArray has height at least HEIGHT
         // by creating new segments at root if necessary
+        // Sets INSTALLED if one of the segments allocated here was installed
 
         loop {
             let root = self.root.load(Ordering::Acquire, guard);
             let root_height = root.tag();
             if root_height < height {
-                let new_seg = Segment::new();
-                new_seg.inner[0].store(root.into_usize(), Ordering::Release);
+                let new_root = self.alloc_segment();
+                let slots = unsafe { Segment::slots(new_root) };
+                slots[0].store(root.into_usize(), Ordering::Release);
 
                 let new_root_height = root_height + 1;
-                let new_root = Owned::new(new_seg);
-                self.root.compare_and_set(
+                match self.root.compare_and_set(
                     root,
                     new_root.with_tag(new_root_height),
                     Ordering::AcqRel,
                     guard,
-                );
+                ) {
+                    Ok(_) => *installed = true,
+                    Err(e) => unsafe { self.free_segment(e.new) },
+                }
             } else {
                 break;
             }
         }
     }
 
-    fn get_val_at_index(&self, index: usize, guard: &Guard) -> &Atomic<T> {
-        // Goes down the segments to get pointer value stored at INDEX
+    fn get_val_at_index(&self, index: usize, installed: &mut bool, guard: &Guard) -> &AtomicUsize {
+        // Goes down the segments to get the slot storing the value at INDEX
         // Initializes child segments if necessary
+        // Sets INSTALLED if one of the segments allocated here was installed
 
-        let mut reference = &self.root;
+        // Each child is loaded once and then gone down into. Loading it again could see null,
+        // since compact_concurrent may detach it in between
+
+        let mut root = self.root.load(Ordering::Acquire, guard);
         loop {
-            let root = (*reference).load(Ordering::Acquire, guard);
             let root_height = root.tag();
 
             let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
             let ind = self.get_bits_at(index, mask, root_height - 1);
 
             if root_height == 1 {
-                return unsafe {
-                    &*(root.deref().get_unchecked(ind) as *const _ as *const Atomic<T>)
-                };
+                return unsafe { &*(&Segment::slots(root)[ind] as *const AtomicUsize) };
             }
 
-            reference = unsafe {
-                &*(root.deref().get_unchecked(ind) as *const _ as *const Atomic<Segment>)
-            };
-            let temp = (*reference).load(Ordering::Acquire, guard);
+            let reference = unsafe { Segment::child(root, ind) };
+            let mut temp = reference.load(Ordering::Acquire, guard);
             if temp.is_null() {
                 let new_child_height = root_height - 1;
-                let new_child = Owned::new(Segment::new());
-                (*reference).compare_and_set(
+                let new_child = self.alloc_segment();
+                match reference.compare_and_set(
                     temp,
                     new_child.with_tag(new_child_height),
                     Ordering::AcqRel,
                     guard,
-                );
+                ) {
+                    Ok(new_child) => {
+                        *installed = true;
+                        temp = new_child;
+                    }
+                    Err(e) => {
+                        unsafe { self.free_segment(e.new) };
+                        temp = e.current;
+                    }
+                }
+            }
+            // Start loading the child's slot while the next iteration computes its index
+            let ind = self.get_bits_at(index, mask, root_height - 2);
+            prefetch(unsafe { Segment::slots(temp).as_ptr().add(ind) });
+            root = temp;
+        }
+    }
+
+    fn get_segment_slot(
+        &self,
+        index: usize,
+        height: usize,
+        guard: &Guard,
+    ) -> &Atomic<Segment<SEGMENT_LOGSIZE>> {
+        // Goes down the segments to the slot pointing at the HEIGHT segment containing INDEX
+        // The segments above it must already exist
+
+        let mut reference = &self.root;
+        loop {
+            let root = (*reference).load(Ordering::Acquire, guard);
+            let root_height = root.tag();
+
+            let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
+            let ind = self.get_bits_at(index, mask, root_height - 1);
+
+            reference = unsafe { &*(Segment::child(root, ind) as *const _) };
+            if root_height == height + 1 {
+                return reference;
             }
         }
     }
 
+    fn get_leaf_segment<'g>(
+        &self,
+        index: usize,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Shared<'g, Segment<SEGMENT_LOGSIZE>> {
+        // Goes down the segments to the height-1 segment containing INDEX
+        // Returns null instead of allocating if the path does not exist
+
+        let segment = self.root.load(ord, guard);
+        if segment.is_null() || segment.tag() < self.get_required_height(index) {
+            return Shared::null();
+        }
+        self.descend_to_leaf(segment, index, ord, guard)
+    }
+
+    fn descend_to_leaf<'g>(
+        &self,
+        mut segment: Shared<'g, Segment<SEGMENT_LOGSIZE>>,
+        index: usize,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Shared<'g, Segment<SEGMENT_LOGSIZE>> {
+        // Goes down from SEGMENT, tall enough to contain INDEX, to the height-1 segment containing
+        // INDEX. Returns null if the path does not exist
+
+        let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
+        let mut height = segment.tag();
+        while height > 1 {
+            let ind = self.get_bits_at(index, mask, height - 1);
+            segment = unsafe { Segment::child(segment, ind) }.load(ord, guard);
+            if segment.is_null() {
+                return Shared::null();
+            }
+            height -= 1;
+        }
+        segment
+    }
+
+    fn load_from_leaf_segment<'g>(
+        &self,
+        segment: Shared<'g, Segment<SEGMENT_LOGSIZE>>,
+        index: usize,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Shared<'g, T> {
+        // Loads the element at INDEX from the height-1 SEGMENT returned by get_leaf_segment
+
+        if segment.is_null() {
+            return Shared::null();
+        }
+        let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
+        let ind = self.get_bits_at(index, mask, 0);
+        let leaf = unsafe { &*(&Segment::slots(segment)[ind] as *const _ as *const Atomic<T>) };
+        leaf.load(ord, guard)
+    }
+
+    fn try_get_slot<'g>(
+        &self,
+        index: usize,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Option<&'g AtomicUsize> {
+        // Returns the slot at INDEX if its segment exists, without allocating
+
+        let segment = self.get_leaf_segment(index, ord, guard);
+        if segment.is_null() {
+            return None;
+        }
+        let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
+        let ind = self.get_bits_at(index, mask, 0);
+        Some(unsafe { &Segment::slots(segment)[ind] })
+    }
+
     /// Returns the reference to the `Atomic` pointer at `index`. Allocates new segments if
     /// necessary.
+    ///
+    /// The reference stays valid, and keeps referring to the same slot, while the tree grows. See
+    /// the pointer stability section of the type's documentation.
     pub fn get(&self, index: usize, guard: &Guard) -> &Atomic<T> {
-        let msb = self.get_msb_index(index);
+        let slot = self.get_slot(index, &mut false, guard);
+        unsafe { &*(slot as *const _ as *const Atomic<T>) }
+    }
 
+    /// Like `get`, but neither grows the tree nor allocates: it only goes down the existing
+    /// segments, and returns `None` if the one holding `index` doesn't exist yet.
+    ///
+    /// This skips the height computation and root check of `get`, which is worth it on hot paths
+    /// that know the index is in range, e.g. because `get` succeeded on it before.
+    ///
+    /// # Safety
+    ///
+    /// The tree must already be tall enough for `index`, i.e. some earlier `get` or
+    /// `prealloc_dense` must have covered an index at least as large. The root never gets shorter,
+    /// so this stays true once it holds. Otherwise the high bits of `index` are ignored and an
+    /// unrelated slot may be returned, or a null root dereferenced.
+    pub unsafe fn get_existing<'g>(
+        &'g self,
+        index: usize,
+        guard: &'g Guard,
+    ) -> Option<&'g Atomic<T>> {
+        let root = self.root.load(Ordering::Acquire, guard);
+        debug_assert!(!root.is_null() && root.tag() >= self.get_required_height(index));
+        let segment = self.descend_to_leaf(root, index, Ordering::Acquire, guard);
+        if segment.is_null() {
+            return None;
+        }
+        let mask: usize = (1 << SEGMENT_LOGSIZE) - 1;
+        let slot = &Segment::slots(segment)[self.get_bits_at(index, mask, 0)];
+        Some(&*(slot as *const _ as *const Atomic<T>))
+    }
+
+    /// Stores `value` at `index`. Allocates new segments if necessary, like `get`.
+    ///
+    /// The stored elements are not owned by the array, so an element previously stored at `index`
+    /// is overwritten without being reclaimed.
+    pub fn store(&self, index: usize, value: Owned<T>, guard: &Guard) {
+        self.get(index, guard).store(value, Ordering::Release);
+    }
+
+    /// Loads the pointer at `index`. Returns null if nothing was stored there, without allocating
+    /// the segment holding it if it doesn't exist yet.
+    pub fn load<'g>(&self, index: usize, guard: &'g Guard) -> Shared<'g, T> {
+        self.peek(index, guard).unwrap_or_else(Shared::null)
+    }
+
+    /// Returns a clone of the element at `index`, or `None` if there is none. Doesn't allocate,
+    /// like `load`.
+    ///
+    /// This relies on elements being freed only once no guard can still load them, e.g. through
+    /// `Guard::defer_destroy`. Freeing an element any earlier takes `unsafe` code, which then has
+    /// to ensure no `snapshot` is in flight.
+    pub fn snapshot(&self, index: usize, guard: &Guard) -> Option<T>
+    where
+        T: Clone,
+    {
+        unsafe { self.load(index, guard).as_ref() }.cloned()
+    }
+
+    /// Stores `new` at `index` and returns the previous pointer. Allocates new segments if
+    /// necessary, like `get`.
+    pub fn swap<'g>(
+        &self,
+        index: usize,
+        new: Shared<'g, T>,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Shared<'g, T> {
+        self.get(index, guard).swap(new, ord, guard)
+    }
+
+    /// Stores `new` at `index` if the pointer there is still `current`. Returns `new` on success
+    /// and the pointer found instead on failure. Allocates new segments if necessary, like `get`.
+    pub fn compare_exchange<'g>(
+        &self,
+        index: usize,
+        current: Shared<'g, T>,
+        new: Shared<'g, T>,
+        ord: Ordering,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, T>, Shared<'g, T>> {
+        self.get(index, guard)
+            .compare_and_set(current, new, ord, guard)
+            .map_err(|e| e.current)
+    }
+
+    /// Like `get`, but also returns whether this call installed a new segment. A segment that was
+    /// allocated but lost the race against another thread's segment is freed and doesn't count.
+    pub fn get_tracked<'g>(&'g self, index: usize, guard: &'g Guard) -> (&'g Atomic<T>, bool) {
+        let mut installed = false;
+        let slot = self.get_slot(index, &mut installed, guard);
+        (
+            unsafe { &*(slot as *const _ as *const Atomic<T>) },
+            installed,
+        )
+    }
+
+    fn get_slot(&self, index: usize, installed: &mut bool, guard: &Guard) -> &AtomicUsize {
         let root = self.root.load(Ordering::Acquire, guard);
         if root.is_null() {
             let new_root_height = 1;
-            let new_root = Owned::new(Segment::new());
-            self.root.compare_and_set(
+            let new_root = self.alloc_segment();
+            match self.root.compare_and_set(
                 root,
                 new_root.with_tag(new_root_height),
                 Ordering::AcqRel,
                 guard,
+            ) {
+                Ok(_) => *installed = true,
+                Err(e) => unsafe { self.free_segment(e.new) },
+            }
+        }
+
+        self.ensure_root_height(self.get_required_height(index), installed, guard);
+        self.get_val_at_index(index, installed, guard)
+    }
+
+    /// Loads the pointer at `index` without allocating, or returns `None` if the segment holding
+    /// it does not exist yet.
+    pub fn peek<'g>(&self, index: usize, guard: &'g Guard) -> Option<Shared<'g, T>> {
+        let slot = self.try_get_slot(index, Ordering::Acquire, guard)?;
+        let slot = unsafe { &*(slot as *const _ as *const Atomic<T>) };
+        Some(slot.load(Ordering::Acquire, guard))
+    }
+
+    /// Allocates every segment needed for indices `0..n`, so that `get` on them never allocates
+    /// afterwards.
+    pub fn prealloc_dense(&self, n: usize, guard: &Guard) {
+        if n == 0 {
+            return;
+        }
+        let mut installed = false;
+        self.get_slot(n - 1, &mut installed, guard);
+
+        // One index per leaf segment is enough, the root already covers all of them
+        for index in (0..n - 1).step_by(1 << SEGMENT_LOGSIZE) {
+            self.get_val_at_index(index, &mut installed, guard);
+        }
+    }
+
+    /// Loads the pointer at `index` with `Relaxed` loads along the whole path, without allocating.
+    /// Returns null if no segment has been allocated for `index` yet.
+    ///
+    /// # Safety
+    ///
+    /// The relaxed loads do not synchronize with the stores that installed the segments and the
+    /// element. The caller must guarantee that whatever stored them happens-before this call
+    /// through some other mechanism (e.g. a lock, a channel, or an `Acquire` load of a flag the
+    /// writer set with `Release` after its last store). Without that, this may read a segment or
+    /// element whose initialization is not yet visible.
+    pub unsafe fn load_relaxed<'g>(&self, index: usize, guard: &'g Guard) -> Shared<'g, T> {
+        let segment = self.get_leaf_segment(index, Ordering::Relaxed, guard);
+        self.load_from_leaf_segment(segment, index, Ordering::Relaxed, guard)
+    }
+
+    /// Returns a mutable reference to the element at `index`, or `None` if it is null. Never
+    /// allocates.
+    ///
+    /// # Safety
+    ///
+    /// For as long as the returned reference lives, no other thread may access the element at
+    /// `index`, neither through this array nor through any other pointer to it, and the element
+    /// must not be replaced or freed. This is meant for regions of the array that are guarded by
+    /// external synchronization, e.g. a lock per index:
+    ///
+    /// ```ignore
+    /// let locks: Vec<Mutex<()>> = (0..n).map(|_| Mutex::new(())).collect();
+    ///
+    /// let _held = locks[i].lock().unwrap();
+    /// if let Some(elem) = unsafe { array.leaf_mut(i, &guard) } {
+    ///     *elem += 1;
+    /// }
+    /// ```
+    ///
+    /// The element must also have been stored before the call, which the lock takes care of here if
+    /// the store happened while holding it.
+    ///
+    /// The `&mut T` is handed out from `&self` because the element is not part of the array: the
+    /// array only stores a pointer to it, and the exclusive access above is what makes the
+    /// reference unique, not a borrow of the array.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn leaf_mut<'g>(&'g self, index: usize, guard: &'g Guard) -> Option<&'g mut T> {
+        let slot = self.try_get_slot(index, Ordering::Acquire, guard)?;
+        let mut leaf = (*(slot as *const _ as *const Atomic<T>)).load(Ordering::Acquire, guard);
+        if leaf.is_null() {
+            return None;
+        }
+        Some(leaf.deref_mut())
+    }
+
+    /// Loads the pointers at all `indices` without allocating, in input order. Indices that have
+    /// no segment yet read as null.
+    ///
+    /// The indices are visited in sorted order so that indices sharing a leaf segment walk the tree
+    /// only once.
+    pub fn load_many<'g>(&'g self, indices: &[usize], guard: &'g Guard) -> Vec<Shared<'g, T>> {
+        let mut order: Vec<usize> = (0..indices.len()).collect();
+        order.sort_unstable_by_key(|&i| indices[i]);
+
+        let mut result = vec![Shared::null(); indices.len()];
+        let mut cached: Option<(usize, Shared<'g, Segment<SEGMENT_LOGSIZE>>)> = None;
+        for i in order {
+            let index = indices[i];
+            let prefix = index >> SEGMENT_LOGSIZE;
+            let segment = match cached {
+                Some((cached_prefix, segment)) if cached_prefix == prefix => segment,
+                _ => {
+                    let segment = self.get_leaf_segment(index, Ordering::Acquire, guard);
+                    cached = Some((prefix, segment));
+                    segment
+                }
+            };
+            result[i] = self.load_from_leaf_segment(segment, index, Ordering::Acquire, guard);
+        }
+        result
+    }
+
+    fn _for_each<'g, F>(
+        &self,
+        segment: Shared<'g, Segment<SEGMENT_LOGSIZE>>,
+        height: usize,
+        prefix: usize,
+        f: &mut F,
+        guard: &'g Guard,
+    ) where
+        T: 'g,
+        F: FnMut(usize, Shared<'g, T>),
+    {
+        // Visit children by DFS traversal, in index order
+        // PREFIX is the index bits above this segment
+
+        for i in 0..(1 << SEGMENT_LOGSIZE) {
+            let index = (prefix << SEGMENT_LOGSIZE) | i;
+            if height == 1 {
+                let leaf =
+                    unsafe { &*(&Segment::slots(segment)[i] as *const _ as *const Atomic<T>) };
+                let leaf = leaf.load(Ordering::Acquire, guard);
+                if !leaf.is_null() {
+                    f(index, leaf);
+                }
+                continue;
+            }
+
+            let child = unsafe { Segment::child(segment, i) }.load(Ordering::Acquire, guard);
+            if !child.is_null() {
+                self._for_each(child, height - 1, index, f, guard);
+            }
+        }
+    }
+
+    /// Calls `f` with the index and pointer of every non-null element.
+    ///
+    /// Elements are visited in strictly ascending index order. Under concurrent stores, an element
+    /// stored during the traversal may or may not be visited.
+    pub fn for_each<'g, F>(&self, mut f: F, guard: &'g Guard)
+    where
+        T: 'g,
+        F: FnMut(usize, Shared<'g, T>),
+    {
+        let root = self.root.load(Ordering::Acquire, guard);
+        if root.is_null() {
+            return;
+        }
+        self._for_each(root, root.tag(), 0, &mut f, guard);
+    }
+
+    /// Folds `f` over the index and pointer of every non-null element, in the same order as
+    /// `for_each`.
+    pub fn fold<'g, B, F>(&self, init: B, mut f: F, guard: &'g Guard) -> B
+    where
+        T: 'g,
+        F: FnMut(B, usize, Shared<'g, T>) -> B,
+    {
+        let mut acc = Some(init);
+        self.for_each(
+            |index, ptr| acc = Some(f(acc.take().unwrap(), index, ptr)),
+            guard,
+        );
+        acc.unwrap()
+    }
+
+    /// Returns the height of the segment tree, 0 if no segment has been allocated yet. An array of
+    /// height `h` addresses indices below `1 << (h * SEGMENT_LOGSIZE)`.
+    pub fn height(&self, guard: &Guard) -> usize {
+        self.root.load(Ordering::Acquire, guard).tag()
+    }
+
+    /// Calls `f` with every allocated segment and its height, parents before their children.
+    fn for_each_segment<'g, F>(&self, mut f: F, guard: &'g Guard)
+    where
+        F: FnMut(Shared<'g, Segment<SEGMENT_LOGSIZE>>, usize),
+    {
+        let root = self.root.load(Ordering::Acquire, guard);
+        if root.is_null() {
+            return;
+        }
+        let mut stack = vec![(root, root.tag())];
+        while let Some((segment, height)) = stack.pop() {
+            f(segment, height);
+            if height == 1 {
+                continue;
+            }
+            for i in 0..(1 << SEGMENT_LOGSIZE) {
+                let child = unsafe { Segment::child(segment, i) }.load(Ordering::Acquire, guard);
+                if !child.is_null() {
+                    stack.push((child, height - 1));
+                }
+            }
+        }
+    }
+
+    /// Returns the number of allocated segments, internal and leaf ones alike.
+    pub fn segment_count(&self, guard: &Guard) -> usize {
+        let mut count = 0;
+        self.for_each_segment(|_, _| count += 1, guard);
+        count
+    }
+
+    /// Returns the bytes taken by the allocated segments, not counting the elements they point to.
+    pub fn memory_bytes(&self, guard: &Guard) -> usize {
+        self.segment_count(guard) * Segment::<SEGMENT_LOGSIZE>::layout().size()
+    }
+
+    /// Returns a read-only view of the array that can never allocate segments.
+    pub fn reader<'g>(&'g self, guard: &'g Guard) -> ArrayReader<'g, T, SEGMENT_LOGSIZE, A> {
+        ArrayReader { array: self, guard }
+    }
+
+    /// Moves every element at an index `>= index` into a new array, which builds its own
+    /// segments. The moved slots are nulled in `self`, but the segments holding them are kept.
+    pub fn split_off(&mut self, index: usize) -> Self
+    where
+        A: Clone,
+    {
+        let mut other = Self::with_allocator(self.alloc.clone());
+        other.pool = self.pool.clone();
+        unsafe {
+            let guard = unprotected();
+            self.for_each(
+                |i, leaf| {
+                    if i >= index {
+                        other.get(i, guard).store(leaf, Ordering::Relaxed);
+                        self.get(i, guard).store(Shared::null(), Ordering::Relaxed);
+                    }
+                },
+                guard,
             );
         }
+        other
+    }
 
-        if msb % SEGMENT_LOGSIZE == 0 {
-            self.ensure_root_height(msb / SEGMENT_LOGSIZE, guard);
-        } else {
-            self.ensure_root_height(msb / SEGMENT_LOGSIZE + 1, guard);
+    /// Moves the elements into a `Vec` of length `1 << SEGMENT_LOGSIZE` indexed like the array,
+    /// if the tree is at most one segment high. Returns `None` if it is taller, in which case the
+    /// elements are left where they are and only the segments are freed.
+    ///
+    /// # Safety
+    ///
+    /// Every stored pointer must be uniquely owned by this array: nothing else may free the
+    /// element or keep using it, since dropping the returned `Owned`s drops the elements.
+    pub unsafe fn into_flat(self) -> Option<Vec<Option<Owned<T>>>> {
+        let guard = unprotected();
+        let root = self.root.load(Ordering::Relaxed, guard);
+        if root.tag() > 1 {
+            return None;
+        }
+
+        let mut flat: Vec<Option<Owned<T>>> = (0..1 << SEGMENT_LOGSIZE).map(|_| None).collect();
+        self.for_each(|i, leaf| flat[i] = Some(leaf.into_owned()), guard);
+        Some(flat)
+    }
+}
+
+/// Read-only view of a `GrowableArray`, returned by `GrowableArray::reader`.
+///
+/// It only offers reads that never allocate, so a read path holding a reader can't grow the tree
+/// by accident. Indices whose segments have not been allocated yet read as null.
+#[derive(Debug)]
+pub struct ArrayReader<'g, T, const SEGMENT_LOGSIZE: usize = 10, A: GlobalAlloc = Global> {
+    array: &'g GrowableArray<T, SEGMENT_LOGSIZE, A>,
+    guard: &'g Guard,
+}
+
+impl<'g, T, const SEGMENT_LOGSIZE: usize, A: GlobalAlloc> ArrayReader<'g, T, SEGMENT_LOGSIZE, A> {
+    /// Loads the pointer at `index`, or null if its segment does not exist.
+    pub fn load(&self, index: usize) -> Shared<'g, T> {
+        match self.try_get(index) {
+            Some(slot) => slot.load(Ordering::Acquire, self.guard),
+            None => Shared::null(),
+        }
+    }
+
+    /// Returns the `Atomic` pointer at `index`, or `None` if its segment does not exist.
+    pub fn try_get(&self, index: usize) -> Option<&'g Atomic<T>> {
+        let slot = self
+            .array
+            .try_get_slot(index, Ordering::Acquire, self.guard)?;
+        Some(unsafe { &*(slot as *const _ as *const Atomic<T>) })
+    }
+}
+
+/// Growable array of `usize` counters.
+///
+/// It reuses the segment tree of `GrowableArray`, but its leaves hold the counters themselves
+/// instead of pointers, so increments are a single `fetch_add` on the leaf. Counters that were
+/// never incremented read as 0.
+#[derive(Debug, Default)]
+pub struct AtomicCounterArray {
+    inner: GrowableArray<()>,
+}
+
+impl AtomicCounterArray {
+    /// Create a new counter array.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to the counter at `index` and returns the previous value. Allocates new
+    /// segments if necessary.
+    pub fn fetch_add(&self, index: usize, delta: usize, guard: &Guard) -> usize {
+        self.inner
+            .get_slot(index, &mut false, guard)
+            .fetch_add(delta, Ordering::AcqRel)
+    }
+
+    /// Returns the counter at `index` without allocating.
+    pub fn load(&self, index: usize, guard: &Guard) -> usize {
+        match self.inner.try_get_slot(index, Ordering::Acquire, guard) {
+            Some(slot) => slot.load(Ordering::Acquire),
+            None => 0,
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<T: Send + Sync, const SEGMENT_LOGSIZE: usize, A: GlobalAlloc + Sync>
+    GrowableArray<T, SEGMENT_LOGSIZE, A>
+{
+    /// Stores `make(i)` at every index in `0..n`, splitting the range across the rayon pool.
+    ///
+    /// Every worker pins its own guard and goes through `store`, so concurrent workers race on
+    /// root growth and segment allocation exactly like independent callers would. As with `store`,
+    /// the stored elements are not owned by the array, and an element previously stored at an
+    /// index in the range is overwritten without being reclaimed.
+    pub fn parallel_fill(&self, n: usize, make: impl Fn(usize) -> Owned<T> + Sync) {
+        (0..n).into_par_iter().for_each(|i| {
+            let guard = crossbeam_epoch::pin();
+            self.store(i, make(i), &guard);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use core::ptr;
+    use crossbeam_epoch as epoch;
+    use std::alloc::System;
+    use std::sync::Barrier;
+    use std::thread;
+
+    /// Allocator that counts the segments allocated and freed through it.
+    #[derive(Debug, Default, Clone)]
+    struct Counting(Arc<(AtomicUsize, AtomicUsize)>);
+
+    impl Counting {
+        fn allocs(&self) -> usize {
+            (self.0).0.load(Ordering::SeqCst)
+        }
+
+        fn frees(&self) -> usize {
+            (self.0).1.load(Ordering::SeqCst)
+        }
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            (self.0).0.fetch_add(1, Ordering::SeqCst);
+            Global.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            (self.0).0.fetch_add(1, Ordering::SeqCst);
+            Global.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            (self.0).1.fetch_add(1, Ordering::SeqCst);
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    /// Allocator that fails once `budget` allocations went through.
+    #[derive(Debug, Clone)]
+    struct Failing {
+        budget: Arc<AtomicUsize>,
+        inner: Counting,
+    }
+
+    unsafe impl GlobalAlloc for Failing {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.alloc_zeroed(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let take = |budget: usize| budget.checked_sub(1);
+            match self
+                .budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, take)
+            {
+                Ok(_) => self.inner.alloc_zeroed(layout),
+                Err(_) => ptr::null_mut(),
+            }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.inner.dealloc(ptr, layout)
+        }
+    }
+
+    /// Global allocator that counts, per thread, the allocations with the layout of a `Segment<4>`.
+    /// Pooled arrays only allocate from `Global`, so `Counting` can't see their segments.
+    struct ThreadCounting;
+
+    thread_local! {
+        static SEGMENT_ALLOCS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn segment_allocs() -> usize {
+        SEGMENT_ALLOCS.with(Cell::get)
+    }
+
+    unsafe impl GlobalAlloc for ThreadCounting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            if layout == Segment::<4>::layout() {
+                let _ = SEGMENT_ALLOCS.try_with(|allocs| allocs.set(allocs.get() + 1));
+            }
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: ThreadCounting = ThreadCounting;
+
+    /// Frees the elements, which the array doesn't own.
+    fn free_elements<T, const SEGMENT_LOGSIZE: usize, A: GlobalAlloc>(
+        array: &GrowableArray<T, SEGMENT_LOGSIZE, A>,
+    ) {
+        unsafe {
+            let guard = unprotected();
+            array.for_each(|_, element| drop(element.into_owned()), guard);
+        }
+    }
+
+    #[test]
+    fn store_and_load_across_heights() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        let indices = [0, 3, 4, 17, 1 << 10, (1 << 20) + 5];
+        for &index in indices.iter() {
+            array.store(index, Owned::new(index * 10), &guard);
+        }
+        for &index in indices.iter() {
+            assert_eq!(array.snapshot(index, &guard), Some(index * 10));
+        }
+        assert_eq!(array.load(5, &guard), Shared::null());
+        assert_eq!(array.height(&guard), 11);
+
+        let mut visited = Vec::new();
+        array.for_each(|index, _| visited.push(index), &guard);
+        assert_eq!(visited, indices);
+        free_elements(&array);
+    }
+
+    /// Indices below `1 << (k * SEGMENT_LOGSIZE)` fit in a tree of height `k`, so the height
+    /// climbs exactly at those boundaries.
+    #[test]
+    fn height_climbs_at_segment_boundaries() {
+        let array = GrowableArray::<usize, 3>::new();
+        let guard = epoch::pin();
+        assert_eq!(array.height(&guard), 0);
+        array.get(0, &guard);
+        assert_eq!(array.height(&guard), 1);
+        for k in 1..(mem::size_of::<usize>() * 8) / 3 {
+            let boundary = 1 << (k * 3);
+            array.get(boundary - 1, &guard);
+            assert_eq!(array.height(&guard), k);
+            array.get(boundary, &guard);
+            assert_eq!(array.height(&guard), k + 1);
+            array.get(boundary + 1, &guard);
+            assert_eq!(array.height(&guard), k + 1);
+        }
+        array.get(usize::MAX, &guard);
+        assert_eq!(
+            array.height(&guard),
+            (mem::size_of::<usize>() * 8).div_ceil(3)
+        );
+    }
+
+    #[test]
+    fn load_does_not_allocate() {
+        let alloc = Counting::default();
+        let array = GrowableArray::<usize, 4, _>::with_allocator(alloc.clone());
+        let guard = epoch::pin();
+        assert_eq!(array.load(1 << 20, &guard), Shared::null());
+        assert_eq!(array.reader(&guard).load(3), Shared::null());
+        assert_eq!(alloc.allocs(), 0);
+
+        array.get(1 << 20, &guard);
+        assert_eq!(alloc.allocs(), array.segment_count(&guard));
+        drop(array);
+        assert_eq!(alloc.frees(), alloc.allocs());
+    }
+
+    /// The tallest tree possible for the segment size, with a few branches, is dropped without
+    /// recursing and frees each of its segments once.
+    #[test]
+    fn drop_frees_tall_trees() {
+        let alloc = Counting::default();
+        let array = GrowableArray::<usize, 2, _>::with_allocator(alloc.clone());
+        let guard = epoch::pin();
+        for &index in [0, 1 << 33, usize::MAX >> 1, usize::MAX].iter() {
+            array.get(index, &guard);
+        }
+        assert_eq!(array.height(&guard), mem::size_of::<usize>() * 4);
+        let segments = array.segment_count(&guard);
+        assert_eq!(alloc.allocs(), segments);
+        drop(array);
+        assert_eq!(alloc.frees(), segments);
+    }
+
+    /// Every thread races to grow the root and to install the same segments, so most of the
+    /// segments allocated lose their CAS. Those have to be freed as well.
+    #[test]
+    fn segments_losing_the_cas_are_freed() {
+        let alloc = Counting::default();
+        for _ in 0..50 {
+            let array = GrowableArray::<usize, 2, _>::with_allocator(alloc.clone());
+            thread::scope(|s| {
+                for t in 0..8 {
+                    let array = &array;
+                    s.spawn(move || {
+                        let guard = epoch::pin();
+                        array.get(0, &guard);
+                        array.get((1 << 12) + t % 2, &guard);
+                        array.get(t, &guard);
+                    });
+                }
+            });
+            let guard = epoch::pin();
+            assert_eq!(array.height(&guard), 7);
+            assert!(alloc.allocs() - alloc.frees() >= array.segment_count(&guard));
+        }
+        assert_eq!(alloc.frees(), alloc.allocs());
+    }
+
+    /// Growing the tree puts new segments above the old root, so a slot handed out by `get`
+    /// stays where it was.
+    #[test]
+    fn get_is_stable_across_growth() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        let slot = array.get(5, &guard);
+        slot.store(Owned::new(50), Ordering::Release);
+        assert_eq!(array.height(&guard), 2);
+
+        array.store(1 << 40, Owned::new(1), &guard);
+        assert_eq!(array.height(&guard), 21);
+        assert!(ptr::eq(slot, array.get(5, &guard)));
+        assert_eq!(unsafe { slot.load(Ordering::Acquire, &guard).deref() }, &50);
+        assert_eq!(array.snapshot(5, &guard), Some(50));
+        free_elements(&array);
+    }
+
+    #[test]
+    fn failed_initial_height_frees_what_it_allocated() {
+        for budget in 0..5 {
+            let counting = Counting::default();
+            let alloc = Failing {
+                budget: Arc::new(AtomicUsize::new(budget)),
+                inner: counting.clone(),
+            };
+            let array = GrowableArray::<usize, 4, _>::try_with_initial_height_in(5, alloc);
+            assert_eq!(array.err(), Some(AllocError));
+            assert_eq!(counting.allocs(), budget);
+            assert_eq!(counting.frees(), budget);
+        }
+
+        let counting = Counting::default();
+        let alloc = Failing {
+            budget: Arc::new(AtomicUsize::new(5)),
+            inner: counting.clone(),
+        };
+        let array = GrowableArray::<usize, 4, _>::try_with_initial_height_in(5, alloc).unwrap();
+        assert_eq!(array.height(&epoch::pin()), 5);
+        drop(array);
+        assert_eq!(counting.frees(), 5);
+    }
+
+    /// Allocates and compacts away the same 32 segments over and over. With a pool, only the first
+    /// round allocates.
+    #[test]
+    fn pool_recycles_segments_under_churn() {
+        let churn = |array: GrowableArray<usize, 4>| {
+            let before = segment_allocs();
+            let guard = unsafe { unprotected() };
+            for _ in 0..100 {
+                for i in 0..16 {
+                    array.get(i << 8, guard);
+                }
+                assert_eq!(unsafe { array.compact_concurrent(guard) }, 32);
+            }
+            segment_allocs() - before
+        };
+        // Plus the root
+        assert_eq!(churn(GrowableArray::new()), 100 * 32 + 1);
+        assert_eq!(churn(GrowableArray::with_segment_pool(32)), 32 + 1);
+    }
+
+    /// Writers store and clear their own indices while another thread keeps compacting. A read
+    /// sees either nothing or the right element, and once the writers are done, everything below
+    /// the root can be compacted away.
+    #[test]
+    fn compaction_races_stores_and_loads() {
+        let array = GrowableArray::<usize, 4>::new();
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let (array, done) = (&array, &done);
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        let index = (t << 12) | ((i % 16) << 8) | (i % 4);
+                        let guard = epoch::pin();
+                        array.store(index, Owned::new(index), &guard);
+                        let loaded = array.load(index, &guard);
+                        if let Some(value) = unsafe { loaded.as_ref() } {
+                            assert_eq!(*value, index);
+                        }
+                        // May not be the element stored above if that one was lost to compaction
+                        let old =
+                            array
+                                .get(index, &guard)
+                                .swap(Shared::null(), Ordering::AcqRel, &guard);
+                        if !old.is_null() {
+                            unsafe { guard.defer_destroy(old) };
+                        }
+                    }
+                    done.fetch_add(1, Ordering::Release);
+                });
+            }
+            s.spawn(|| {
+                while done.load(Ordering::Acquire) < 4 {
+                    unsafe { array.compact_concurrent(&epoch::pin()) };
+                }
+            });
+        });
+        let guard = unsafe { unprotected() };
+        unsafe { array.compact_concurrent(guard) };
+        assert_eq!(array.segment_count(guard), 1);
+    }
+
+    /// Threads released together all try to install the root for index 0. Only one segment may
+    /// survive, and every other one is freed.
+    #[test]
+    fn racing_root_installs_leak_nothing() {
+        let alloc = Counting::default();
+        for _ in 0..200 {
+            let array = GrowableArray::<usize, 4, _>::with_allocator(alloc.clone());
+            let barrier = Barrier::new(8);
+            let slots: Vec<usize> = thread::scope(|s| {
+                let handles: Vec<_> = (0..8)
+                    .map(|_| {
+                        let (array, barrier) = (&array, &barrier);
+                        s.spawn(move || {
+                            barrier.wait();
+                            array.get(0, &epoch::pin()) as *const _ as usize
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            assert!(slots.iter().all(|&slot| slot == slots[0]));
+            assert_eq!(array.segment_count(&epoch::pin()), 1);
+            assert_eq!(alloc.allocs() - alloc.frees(), 1);
+            drop(array);
+            assert_eq!(alloc.frees(), alloc.allocs());
+        }
+    }
+
+    #[test]
+    fn snapshot_clones_the_element() {
+        let array = GrowableArray::<String>::new();
+        let guard = epoch::pin();
+        array.store(900, Owned::new("owl".to_string()), &guard);
+        let owl = array.snapshot(900, &guard);
+        free_elements(&array);
+        assert_eq!(owl.as_deref(), Some("owl"));
+        assert_eq!(array.snapshot(901, &guard), None);
+        assert_eq!(array.snapshot(1 << 40, &guard), None);
+    }
+
+    #[test]
+    fn into_flat_moves_single_segment() {
+        let array = GrowableArray::<usize, 3>::new();
+        let guard = epoch::pin();
+        array.store(2, Owned::new(20), &guard);
+        array.store(7, Owned::new(70), &guard);
+        let flat = unsafe { array.into_flat() }.unwrap();
+        assert_eq!(flat.len(), 8);
+        let values: Vec<_> = flat.into_iter().map(|v| v.map(|v| *v)).collect();
+        assert_eq!(values[2], Some(20));
+        assert_eq!(values[7], Some(70));
+        assert_eq!(values.iter().flatten().count(), 2);
+
+        let array = GrowableArray::<usize, 3>::new();
+        array.store(8, Owned::new(80), &guard);
+        free_elements(&array);
+        assert!(unsafe { array.into_flat() }.is_none());
+    }
+
+    #[test]
+    fn atomic_counters_add_up() {
+        let counters = AtomicCounterArray::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let guard = epoch::pin();
+                    for i in 0..1000 {
+                        counters.fetch_add(i % 10, 1, &guard);
+                    }
+                });
+            }
+        });
+        let guard = epoch::pin();
+        assert_eq!(counters.load(3, &guard), 400);
+        assert_eq!(counters.load(1 << 30, &guard), 0);
+    }
+
+    #[test]
+    fn split_off_moves_the_upper_indices() {
+        let mut array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        for &index in [1, 5, 9, 100].iter() {
+            array.store(index, Owned::new(index * 10), &guard);
+        }
+        let upper = array.split_off(9);
+        let indices = |array: &GrowableArray<usize, 2>| {
+            array.fold(
+                Vec::new(),
+                |mut v, i, _| {
+                    v.push(i);
+                    v
+                },
+                &guard,
+            )
+        };
+        assert_eq!(indices(&array), [1, 5]);
+        assert_eq!(indices(&upper), [9, 100]);
+        assert_eq!(array.snapshot(9, &guard), None);
+        assert_eq!(upper.snapshot(100, &guard), Some(1000));
+        assert_eq!(upper.snapshot(1, &guard), None);
+        free_elements(&array);
+        free_elements(&upper);
+    }
+
+    #[test]
+    fn load_many_keeps_the_input_order() {
+        let alloc = Counting::default();
+        let array = GrowableArray::<usize, 2, _>::with_allocator(alloc.clone());
+        let guard = epoch::pin();
+        for &index in [3, 17, 1 << 12].iter() {
+            array.store(index, Owned::new(index), &guard);
+        }
+        let allocs = alloc.allocs();
+        let indices = [1 << 12, 3, 4, 17, 3, 1 << 30, 16];
+        let values: Vec<_> = array
+            .load_many(&indices, &guard)
+            .into_iter()
+            .map(|p| unsafe { p.as_ref() }.copied())
+            .collect();
+        assert_eq!(
+            values,
+            [Some(1 << 12), Some(3), None, Some(17), Some(3), None, None]
+        );
+        assert_eq!(alloc.allocs(), allocs);
+        assert!(array.load_many(&[], &guard).is_empty());
+        free_elements(&array);
+    }
+
+    #[test]
+    fn get_tracked_reports_installed_segments() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        assert!(array.get_tracked(0, &guard).1);
+        assert!(!array.get_tracked(0, &guard).1);
+        // Same leaf segment
+        assert!(!array.get_tracked(3, &guard).1);
+        // Grows the root
+        assert!(array.get_tracked(1 << 10, &guard).1);
+        // Only a new leaf below the existing root
+        let before = array.segment_count(&guard);
+        assert!(array.get_tracked(8, &guard).1);
+        assert_eq!(array.segment_count(&guard), before + 1);
+        let (slot, installed) = array.get_tracked(8, &guard);
+        assert!(!installed);
+        assert!(ptr::eq(slot, array.get(8, &guard)));
+    }
+
+    #[test]
+    fn fold_visits_elements_in_index_order() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        assert_eq!(array.fold(7, |acc, _, _| acc + 1, &guard), 7);
+        for &index in [1 << 20, 2, 17, 0].iter() {
+            array.store(index, Owned::new(index + 1), &guard);
+        }
+        let (sum, order) = array.fold(
+            (0, Vec::new()),
+            |(sum, mut order), index, element| {
+                order.push(index);
+                (sum + unsafe { element.deref() }, order)
+            },
+            &guard,
+        );
+        assert_eq!(sum, (1 << 20) + 2 + 17 + 4);
+        assert_eq!(order, [0, 2, 17, 1 << 20]);
+        free_elements(&array);
+    }
+
+    #[test]
+    fn replace_subtree_swaps_one_aligned_range() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        for &index in [5, 16, 20, 31, 32].iter() {
+            array.store(index, Owned::new(index), &guard);
+        }
+        let subtree = GrowableArray::<usize, 2>::new();
+        subtree.store(0, Owned::new(100), &guard);
+        subtree.store(9, Owned::new(109), &guard);
+
+        // The old elements in 16..32 are not freed by the swap
+        let old: Vec<_> = [16, 20, 31]
+            .iter()
+            .map(|&index| array.load(index, &guard))
+            .collect();
+        unsafe { array.replace_subtree(16, 2, subtree, &guard) };
+        for element in old {
+            unsafe { drop(element.into_owned()) };
+        }
+
+        let entries = array.fold(
+            Vec::new(),
+            |mut v, index, element| {
+                v.push((index, unsafe { *element.deref() }));
+                v
+            },
+            &guard,
+        );
+        assert_eq!(entries, [(5, 5), (16, 100), (25, 109), (32, 32)]);
+        free_elements(&array);
+    }
+
+    #[test]
+    fn stores_after_prealloc_dense_do_not_allocate() {
+        const N: usize = 1000;
+        let alloc = Counting::default();
+        let array = GrowableArray::<usize, 2, _>::with_allocator(alloc.clone());
+        let guard = epoch::pin();
+        array.prealloc_dense(N, &guard);
+        let allocs = alloc.allocs();
+        assert_eq!(allocs, array.segment_count(&guard));
+        for index in 0..N {
+            let (slot, installed) = array.get_tracked(index, &guard);
+            assert!(!installed, "index {} allocated", index);
+            slot.store(Owned::new(index), Ordering::Relaxed);
+        }
+        assert_eq!(alloc.allocs(), allocs);
+
+        // The first index past the prefix needs a new leaf
+        assert!(array.get_tracked(N, &guard).1);
+        free_elements(&array);
+    }
+
+    /// With 4 slots per segment: index 0 needs a single leaf, index 4 a second leaf and a root of
+    /// height 2 above both, and index 63 a root of height 3, a segment of height 2 and a leaf.
+    #[test]
+    fn segment_count_matches_the_tree_shape() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        assert_eq!(array.segment_count(&guard), 0);
+        assert_eq!(array.memory_bytes(&guard), 0);
+        let expected = [(0, 1), (3, 1), (4, 3), (63, 6), (62, 6), (16, 8)];
+        for &(index, count) in expected.iter() {
+            array.get(index, &guard);
+            assert_eq!(array.segment_count(&guard), count, "after index {}", index);
+        }
+        assert_eq!(array.memory_bytes(&guard), 8 * 4 * mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn swap_and_compare_exchange_at_several_indices() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        for &index in [0, 5, 1 << 12].iter() {
+            let first = Owned::new(index).into_shared(&guard);
+            assert!(array.swap(index, first, Ordering::AcqRel, &guard).is_null());
+            let second = Owned::new(index + 1).into_shared(&guard);
+            let old = array.swap(index, second, Ordering::AcqRel, &guard);
+            assert_eq!(old, first);
+            unsafe { drop(old.into_owned()) };
+
+            // A stale `current` fails and reports the pointer actually there
+            let third = Owned::new(index + 2).into_shared(&guard);
+            assert_eq!(
+                array.compare_exchange(index, Shared::null(), third, Ordering::AcqRel, &guard),
+                Err(second)
+            );
+            assert_eq!(
+                array.compare_exchange(index, second, third, Ordering::AcqRel, &guard),
+                Ok(third)
+            );
+            unsafe { drop(second.into_owned()) };
+            assert_eq!(array.snapshot(index, &guard), Some(index + 2));
+        }
+        free_elements(&array);
+    }
+
+    #[test]
+    fn get_existing_matches_get_within_the_tree() {
+        let array = GrowableArray::<usize, 2>::new();
+        let guard = epoch::pin();
+        for &index in [0, 6, 100, 1 << 12].iter() {
+            array.store(index, Owned::new(index), &guard);
+        }
+        for &index in [0, 6, 100, 1 << 12].iter() {
+            let slot = unsafe { array.get_existing(index, &guard) }.unwrap();
+            assert!(ptr::eq(slot, array.get(index, &guard)));
         }
-        return self.get_val_at_index(index, guard);
+        // In range, but below a null child
+        assert!(unsafe { array.get_existing(1 << 11, &guard) }.is_none());
+        free_elements(&array);
     }
 }
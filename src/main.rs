@@ -1,9 +1,27 @@
+mod checkpoint;
+mod counter;
+mod frozen;
 mod growable_array;
+mod map;
+mod sharded;
 mod split_ordered_list;
+#[cfg(feature = "ttl")]
+mod ttl;
 
+pub use checkpoint::{Encode, RestoreError};
 use crossbeam_epoch as epoch;
-pub use growable_array::GrowableArray;
-pub use split_ordered_list::SplitOrderedList;
+pub use frozen::FrozenMap;
+pub use growable_array::{AllocError, ArrayReader, AtomicCounterArray, Global, GrowableArray};
+pub use map::{ConcurrentHashMap, IdentityBuildHasher, IdentityHasher, NonblockingMap};
+pub use sharded::ShardedMap;
+#[cfg(feature = "debug")]
+pub use split_ordered_list::BucketDebug;
+pub use split_ordered_list::{
+    Drain, Entry, InsertRejected, Iter, MapError, OccupiedEntry, Probe, SplitOrderedList,
+    StableIter, VacantEntry,
+};
+#[cfg(feature = "ttl")]
+pub use ttl::TtlMap;
 
 fn main() {
     let list = SplitOrderedList::<usize>::new();
@@ -17,11 +35,13 @@ fn main() {
     assert_eq!(list.lookup(&42, &guard), Some(&42));
     assert_eq!(list.lookup(&37, &guard), Some(&37));
 
+    assert_eq!(list.insert(&42, 43, &guard), Err(MapError::KeyExists(43)));
+
     assert_eq!(list.delete(&37, &guard), Ok(&37));
     assert_eq!(list.lookup(&42, &guard), Some(&42));
     assert_eq!(list.lookup(&37, &guard), None);
 
-    assert_eq!(list.delete(&37, &guard), Err(()));
+    assert_eq!(list.delete(&37, &guard), Err(MapError::KeyNotFound));
     assert_eq!(list.lookup(&42, &guard), Some(&42));
     assert_eq!(list.lookup(&37, &guard), None);
 }
@@ -0,0 +1,431 @@
+//! Hash map over arbitrary keys, built on `SplitOrderedList`.
+
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::ptr;
+use core::sync::atomic::Ordering;
+use crossbeam_epoch::{unprotected, Atomic, Guard, Owned, Shared};
+use std::collections::hash_map::RandomState;
+use std::sync::Arc;
+
+use super::split_ordered_list::SplitOrderedList;
+
+/// Lock-free map whose operations run within the epoch pinned by a `Guard`.
+///
+/// References handed out by `lookup` and `delete` stay valid as long as the guard.
+pub trait NonblockingMap<K, V> {
+    /// Looks up `key`.
+    fn lookup<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V>;
+    /// Inserts `value` for `key`, or hands `value` back if `key` is already present.
+    fn insert(&self, key: &K, value: V, guard: &Guard) -> Result<(), V>;
+    /// Deletes the entry for `key` and returns its value, or returns `None` if `key` is absent.
+    fn delete<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V>;
+}
+
+/// `BuildHasher` under which an integer key hashes to itself, so that a `ConcurrentHashMap` places
+/// the key in the same bucket as a `SplitOrderedList` would.
+///
+/// Meant for tests and benchmarks that need predictable bucket placement. Colliding keys are
+/// trivial to choose, so it must not be used with untrusted keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityBuildHasher;
+
+impl BuildHasher for IdentityBuildHasher {
+    type Hasher = IdentityHasher;
+
+    fn build_hasher(&self) -> IdentityHasher {
+        IdentityHasher::default()
+    }
+}
+
+/// Hasher built by `IdentityBuildHasher`. Returns the last integer written to it, and folds other
+/// bytes into the hash as they come.
+#[derive(Debug, Default)]
+pub struct IdentityHasher {
+    hash: u64,
+}
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = self.hash.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.hash = u64::from(n);
+    }
+
+    fn write_u16(&mut self, n: u16) {
+        self.hash = u64::from(n);
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.hash = u64::from(n);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.hash = n;
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.hash = n as u64;
+    }
+}
+
+/// Tag of `Chain::entries` once the chain has become empty for good. The list node holding it is
+/// about to be deleted, and an insert has to wait for that and start a new chain.
+const SEALED: usize = 1;
+
+/// Entries whose keys have the same hash. Almost always there is a single one.
+///
+/// The list can't change the value of a node in place, so the entries sit behind a pointer that is
+/// swapped for an updated copy. Entries are shared between copies, hence the `Arc`.
+#[derive(Debug)]
+struct Chain<K, V> {
+    entries: Atomic<Vec<Arc<(K, V)>>>,
+}
+
+impl<K, V> Chain<K, V> {
+    fn new(entries: Vec<Arc<(K, V)>>) -> Self {
+        Self {
+            entries: Atomic::new(entries),
+        }
+    }
+
+    /// Takes back the entries of a chain that never made it into the list.
+    fn into_entries(self) -> Vec<Arc<(K, V)>> {
+        unsafe {
+            let entries = self
+                .entries
+                .swap(Shared::null(), Ordering::Relaxed, unprotected());
+            *entries.into_owned().into_box()
+        }
+    }
+}
+
+impl<K, V> Drop for Chain<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let entries = self.entries.load(Ordering::Relaxed, unprotected());
+            if !entries.is_null() {
+                drop(entries.into_owned());
+            }
+        }
+    }
+}
+
+/// Lock-free hash map from `K` to `V`.
+///
+/// Keys are hashed with `S` into the key range of `SplitOrderedList`: the 64-bit hash is truncated
+/// to `usize` and its high bit is masked off, leaving a key in `0..2^63` on 64-bit targets. Keys
+/// with the same hash share one list node and are told apart by equality.
+///
+/// The default `RandomState` is seeded differently for every map, so keys chosen to collide in one
+/// map, and pile up in a single bucket, have no reason to collide in another.
+#[derive(Debug)]
+pub struct ConcurrentHashMap<K, V, S = RandomState> {
+    inner: SplitOrderedList<Chain<K, V>>,
+    hasher: S,
+}
+
+impl<K, V> Default for ConcurrentHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> ConcurrentHashMap<K, V> {
+    /// Creates a new map with a randomly seeded hasher.
+    pub fn new() -> Self {
+        Self {
+            inner: SplitOrderedList::new(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K, V, S> ConcurrentHashMap<K, V, S> {
+    /// Creates a new map whose keys are hashed with `hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        Self {
+            inner: SplitOrderedList::new(),
+            hasher,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ConcurrentHashMap<K, V, S> {
+    fn hash(&self, key: &K) -> usize {
+        // Keys of `SplitOrderedList` must have the high bit clear
+        self.hasher.hash_one(key) as usize & (usize::MAX >> 1)
+    }
+
+    pub fn lookup<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V> {
+        let chain = self.inner.lookup(&self.hash(key), guard)?;
+        let entries = chain.entries.load(Ordering::Acquire, guard);
+        unsafe { entries.as_ref() }?
+            .iter()
+            .find(|entry| entry.0 == *key)
+            .map(|entry| &entry.1)
+    }
+
+    /// Inserts `value` for `key`, or hands `value` back if `key` is already present.
+    pub fn insert(&self, key: K, value: V, guard: &Guard) -> Result<(), V> {
+        let hash = self.hash(&key);
+        let mut entry = Arc::new((key, value));
+        loop {
+            let chain = match self.inner.lookup(&hash, guard) {
+                Some(chain) => chain,
+                None => match self.inner.insert(&hash, Chain::new(vec![entry]), guard) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        entry = err.into_value().unwrap().into_entries().pop().unwrap();
+                        continue;
+                    }
+                },
+            };
+
+            let current = chain.entries.load(Ordering::Acquire, guard);
+            if current.tag() == SEALED {
+                // Help deleting the dead chain, then start a new one
+                let _ = self
+                    .inner
+                    .delete_if(&hash, |other| ptr::eq(other, chain), guard);
+                continue;
+            }
+
+            let entries = unsafe { current.deref() };
+            if entries.iter().any(|other| other.0 == entry.0) {
+                let (_, value) = Arc::try_unwrap(entry).unwrap_or_else(|_| unreachable!());
+                return Err(value);
+            }
+            let mut updated = Vec::with_capacity(entries.len() + 1);
+            updated.extend(entries.iter().cloned());
+            updated.push(entry.clone());
+            if chain
+                .entries
+                .compare_and_set(current, Owned::new(updated), Ordering::AcqRel, guard)
+                .is_ok()
+            {
+                unsafe { guard.defer_destroy(current) };
+                return Ok(());
+            }
+        }
+    }
+
+    /// Removes the entry for `key` and returns its value, which stays valid as long as `guard`.
+    /// Returns `None` if `key` is absent.
+    pub fn remove<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V> {
+        let hash = self.hash(key);
+        let chain = self.inner.lookup(&hash, guard)?;
+        loop {
+            let current = chain.entries.load(Ordering::Acquire, guard);
+            // A sealed chain has no entries
+            let entries = unsafe { current.as_ref() }?;
+            let index = entries.iter().position(|entry| entry.0 == *key)?;
+
+            if entries.len() == 1 {
+                // Seal the chain before deleting its node, so that no insert adds to it meanwhile
+                let sealed = Shared::null().with_tag(SEALED);
+                if chain
+                    .entries
+                    .compare_and_set(current, sealed, Ordering::AcqRel, guard)
+                    .is_err()
+                {
+                    continue;
+                }
+                let _ = self
+                    .inner
+                    .delete_if(&hash, |other| ptr::eq(other, chain), guard);
+            } else {
+                let mut updated = entries.clone();
+                updated.remove(index);
+                if chain
+                    .entries
+                    .compare_and_set(current, Owned::new(updated), Ordering::AcqRel, guard)
+                    .is_err()
+                {
+                    continue;
+                }
+            }
+            unsafe { guard.defer_destroy(current) };
+            return Some(&entries[index].1);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, S: BuildHasher> NonblockingMap<K, V> for ConcurrentHashMap<K, V, S> {
+    fn lookup<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V> {
+        self.lookup(key, guard)
+    }
+
+    fn insert(&self, key: &K, value: V, guard: &Guard) -> Result<(), V> {
+        self.insert(key.clone(), value, guard)
+    }
+
+    fn delete<'a>(&'a self, key: &K, guard: &'a Guard) -> Option<&'a V> {
+        self.remove(key, guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sharded::ShardedMap;
+    use super::*;
+    use crossbeam_epoch as epoch;
+    use std::thread;
+
+    /// Hashes every key to the same value, so all keys share one chain.
+    #[derive(Debug, Default)]
+    struct Colliding;
+
+    impl BuildHasher for Colliding {
+        type Hasher = Colliding;
+
+        fn build_hasher(&self) -> Colliding {
+            Colliding
+        }
+    }
+
+    impl Hasher for Colliding {
+        fn finish(&self) -> u64 {
+            42
+        }
+
+        fn write(&mut self, _: &[u8]) {}
+    }
+
+    #[test]
+    fn string_and_tuple_keys_survive_a_resize() {
+        let strings = ConcurrentHashMap::new();
+        let tuples = ConcurrentHashMap::new();
+        let guard = epoch::pin();
+        for i in 0..100 {
+            assert_eq!(strings.insert(format!("key{}", i), i, &guard), Ok(()));
+            assert_eq!(tuples.insert((i, format!("{}", i * 7)), i, &guard), Ok(()));
+        }
+        // Both started with 2 buckets
+        assert!(strings.inner.resize_frontier(&guard).0 > 2);
+        assert!(tuples.inner.resize_frontier(&guard).0 > 2);
+        for i in 0..100 {
+            assert_eq!(strings.lookup(&format!("key{}", i), &guard), Some(&i));
+            assert_eq!(tuples.lookup(&(i, format!("{}", i * 7)), &guard), Some(&i));
+        }
+        assert_eq!(strings.lookup(&"key100".to_string(), &guard), None);
+        assert_eq!(tuples.lookup(&(1, "6".to_string()), &guard), None);
+    }
+
+    #[test]
+    fn identity_hash_keeps_the_key_below_the_high_bit() {
+        let map: ConcurrentHashMap<u64, (), _> =
+            ConcurrentHashMap::with_hasher(IdentityBuildHasher);
+        assert_eq!(map.hash(&37), 37);
+        assert_eq!(map.hash(&u64::MAX), (u64::MAX >> 1) as usize);
+    }
+
+    #[test]
+    fn random_states_place_keys_differently() {
+        let a: ConcurrentHashMap<u64, ()> = ConcurrentHashMap::new();
+        let b: ConcurrentHashMap<u64, ()> = ConcurrentHashMap::new();
+        // Buckets of the keys once there are 1024 of them
+        let buckets = |map: &ConcurrentHashMap<u64, ()>| -> Vec<usize> {
+            (0..64).map(|key| map.hash(&key) % 1024).collect()
+        };
+        assert_ne!(buckets(&a), buckets(&b));
+    }
+
+    /// Keys 1 and 3 share bucket 1 of 2, and are split once the map has 4 buckets or more.
+    #[test]
+    fn identity_hash_splits_a_bucket_on_resize() {
+        let map = ConcurrentHashMap::with_hasher(IdentityBuildHasher);
+        let guard = epoch::pin();
+        map.insert(1_usize, "owl", &guard).unwrap();
+        map.insert(3, "fox", &guard).unwrap();
+        assert_eq!(map.inner.resize_frontier(&guard).0, 2);
+        assert_eq!(map.inner.max_chain_len(&guard), 2);
+
+        map.inner.reserve(100);
+        assert!(map.inner.resize_frontier(&guard).0 >= 4);
+        // Bucket 3 gets its sentinel on the next insert into it
+        assert_eq!(map.remove(&3, &guard), Some(&"fox"));
+        map.insert(3, "fox", &guard).unwrap();
+        assert_eq!(map.inner.max_chain_len(&guard), 1);
+        assert_eq!(map.lookup(&1, &guard), Some(&"owl"));
+        assert_eq!(map.lookup(&3, &guard), Some(&"fox"));
+    }
+
+    #[test]
+    fn colliding_keys_are_told_apart() {
+        let map = ConcurrentHashMap::with_hasher(Colliding);
+        let guard = epoch::pin();
+        assert_eq!(map.insert("fox", 1, &guard), Ok(()));
+        assert_eq!(map.insert("owl", 2, &guard), Ok(()));
+        assert_eq!(map.insert("fox", 3, &guard), Err(3));
+        assert_eq!(map.lookup(&"fox", &guard), Some(&1));
+        assert_eq!(map.lookup(&"owl", &guard), Some(&2));
+        assert_eq!(map.lookup(&"cat", &guard), None);
+
+        assert_eq!(map.remove(&"fox", &guard), Some(&1));
+        assert_eq!(map.remove(&"fox", &guard), None);
+        assert_eq!(map.lookup(&"owl", &guard), Some(&2));
+        assert_eq!(map.remove(&"owl", &guard), Some(&2));
+        assert_eq!(map.insert("cat", 4, &guard), Ok(()));
+        assert_eq!(map.lookup(&"cat", &guard), Some(&4));
+    }
+
+    /// Threads insert and remove their own keys in one shared chain, sealing and recreating it
+    /// along the way. Every key must be found right after its insert.
+    #[test]
+    fn concurrent_colliding_inserts_and_removes() {
+        let map = ConcurrentHashMap::with_hasher(Colliding);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        let guard = epoch::pin();
+                        let key = t * 10_000 + i;
+                        assert_eq!(map.insert(key, i, &guard), Ok(()));
+                        assert_eq!(map.lookup(&key, &guard), Some(&i));
+                        if i % 2 == 0 {
+                            assert_eq!(map.remove(&key, &guard), Some(&i));
+                        }
+                    }
+                });
+            }
+        });
+        let guard = epoch::pin();
+        for t in 0..4 {
+            for i in 0..2000 {
+                let expected = if i % 2 == 0 { None } else { Some(&i) };
+                assert_eq!(map.lookup(&(t * 10_000 + i), &guard), expected);
+            }
+        }
+    }
+
+    /// The trait is object safe: every implementation fits behind the same `dyn NonblockingMap`.
+    #[test]
+    fn maps_work_as_trait_objects() {
+        let maps: Vec<Box<dyn NonblockingMap<usize, usize>>> = vec![
+            Box::new(SplitOrderedList::new()),
+            Box::new(ConcurrentHashMap::<usize, usize>::new()),
+            Box::new(ShardedMap::with_shards(4)),
+        ];
+        let guard = epoch::pin();
+        for map in maps.iter() {
+            for key in 0..100 {
+                assert_eq!(map.insert(&key, key * 2, &guard), Ok(()));
+            }
+            assert_eq!(map.insert(&7, 0, &guard), Err(0));
+            assert_eq!(map.lookup(&7, &guard), Some(&14));
+            assert_eq!(map.delete(&7, &guard), Some(&14));
+            assert_eq!(map.delete(&7, &guard), None);
+            assert_eq!(map.lookup(&7, &guard), None);
+            assert_eq!(map.lookup(&99, &guard), Some(&198));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Map partitioned over several independent split-ordered lists.
+
+use crossbeam_epoch::Guard;
+
+use super::map::NonblockingMap;
+use super::split_ordered_list::{MapError, SplitOrderedList};
+
+/// Map that spreads its keys over a fixed number of independent `SplitOrderedList`s, so that
+/// writers to different shards never contend on the same list or counters.
+///
+/// Key `k` lives in shard `k % n` under the key `k / n`. Dividing, rather than keeping `k` as is,
+/// keeps the keys of a shard contiguous, so they still spread over all the buckets of that shard.
+#[derive(Debug)]
+pub struct ShardedMap<V> {
+    shards: Box<[SplitOrderedList<V>]>,
+}
+
+impl<V> ShardedMap<V> {
+    /// Creates a map with `n` shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn with_shards(n: usize) -> Self {
+        assert!(n > 0, "a sharded map needs at least one shard");
+        Self {
+            shards: (0..n).map(|_| SplitOrderedList::new()).collect(),
+        }
+    }
+
+    /// Returns the shard holding `key` and the key it has there.
+    fn route(&self, key: &usize) -> (&SplitOrderedList<V>, usize) {
+        // Same key range as a single list, even though `k / n` would fit for larger keys
+        SplitOrderedList::<V>::assert_valid_key(*key);
+        let n = self.shards.len();
+        (&self.shards[*key % n], *key / n)
+    }
+
+    /// Returns the value for `key`.
+    pub fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        let (shard, key) = self.route(key);
+        shard.lookup(&key, guard)
+    }
+
+    /// Inserts `value` for `key`, or fails with `MapError::KeyExists` holding `value` if the key
+    /// is already present.
+    pub fn insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), MapError<V>> {
+        let (shard, key) = self.route(key);
+        shard.insert(&key, value, guard)
+    }
+
+    /// Deletes the entry for `key` and returns its value, or fails with `MapError::KeyNotFound`.
+    pub fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Result<&'a V, MapError<V>> {
+        let (shard, key) = self.route(key);
+        shard.delete(&key, guard)
+    }
+
+    /// Returns the number of entries, summed over the shards. Under concurrent mutation the shards
+    /// are counted one after the other, so the sum is only approximate.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(SplitOrderedList::len).sum()
+    }
+
+    /// Returns whether the map has no entries. Same caveat as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(SplitOrderedList::is_empty)
+    }
+}
+
+impl<V> NonblockingMap<usize, V> for ShardedMap<V> {
+    fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.lookup(key, guard)
+    }
+
+    fn insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), V> {
+        self.insert(key, value, guard)
+            .map_err(|err| err.into_value().unwrap())
+    }
+
+    fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.delete(key, guard).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_epoch as epoch;
+    use std::thread;
+
+    #[test]
+    fn keys_spread_over_the_shards() {
+        let map = ShardedMap::with_shards(3);
+        let guard = epoch::pin();
+        for key in 0..30 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert!(map.shards.iter().all(|shard| shard.len() == 10));
+        assert_eq!(map.insert(&4, 0, &guard), Err(MapError::KeyExists(0)));
+        assert_eq!(map.delete(&4, &guard), Ok(&4));
+        assert_eq!(map.lookup(&4, &guard), None);
+        assert_eq!(map.lookup(&5, &guard), Some(&5));
+        assert_eq!(map.len(), 29);
+    }
+
+    #[test]
+    fn concurrent_inserts_all_land() {
+        let map = ShardedMap::with_shards(4);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    let guard = epoch::pin();
+                    for i in 0..1000 {
+                        map.insert(&(i * 4 + t), i, &guard).unwrap();
+                    }
+                });
+            }
+        });
+        let guard = epoch::pin();
+        assert_eq!(map.len(), 4000);
+        assert!((0..4000).all(|key| map.lookup(&key, &guard) == Some(&(key / 4))));
+    }
+
+    #[test]
+    #[should_panic]
+    fn needs_a_shard() {
+        ShardedMap::<()>::with_shards(0);
+    }
+
+    /// Keys on both sides of every shard boundary of 16 shards route to the shard `key % 16`
+    /// under the key `key / 16`, for every operation.
+    #[test]
+    fn sixteen_shards_route_boundary_keys() {
+        let map = ShardedMap::with_shards(16);
+        let guard = epoch::pin();
+        let keys: Vec<_> = (0..8).flat_map(|i| [i * 16, i * 16 + 15]).collect();
+        for &key in keys.iter() {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.len(), keys.len());
+        for &key in keys.iter() {
+            assert_eq!(map.lookup(&key, &guard), Some(&key));
+            assert_eq!(map.shards[key % 16].lookup(&(key / 16), &guard), Some(&key));
+        }
+        assert_eq!(map.shards[0].len(), 8);
+        assert_eq!(map.shards[15].len(), 8);
+        assert!(map.shards[1..15].iter().all(SplitOrderedList::is_empty));
+
+        assert_eq!(map.delete(&31, &guard), Ok(&31));
+        assert_eq!(map.lookup(&31, &guard), None);
+        assert_eq!(map.lookup(&15, &guard), Some(&15));
+        assert_eq!(map.shards[15].len(), 7);
+        assert_eq!(map.len(), keys.len() - 1);
+        assert!(!map.is_empty());
+    }
+}
@@ -1,26 +1,354 @@
 //! Split-ordered linked list.
 
+use core::cmp;
+use core::convert::TryFrom;
+use core::fmt;
+use core::iter::FromIterator;
 use core::mem;
+use core::ptr;
+#[cfg(feature = "metrics")]
+use core::sync::atomic::AtomicU64;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use crossbeam_epoch::{Guard, Owned};
+use crossbeam_epoch::{unprotected, Atomic, Guard, Owned, Shared};
+use crossbeam_utils::CachePadded;
 use lockfree::list::{Cursor, List, Node};
-// use cs492_concur_homework::map::NonblockingMap;
+use std::sync::Arc;
 
+use super::checkpoint::{self, Encode, RestoreError};
+use super::counter::StripedCounter;
+use super::frozen::FrozenMap;
 use super::growable_array::GrowableArray;
+use super::map::NonblockingMap;
 
-/// Lock-free map from `usize` in range [0, 2^63-1] to `V`.
+/// Value of a list node.
 ///
-/// NOTE: We don't care about hashing in this homework for simplicity.
+/// `Node` does not expose its key, so the split-order key is kept here as well for traversals that
+/// have to step past the current node.
+///
+/// The value sits behind a pointer so that `update` can swap it. Readers load it with their guard,
+/// and a replaced value is retired through the guard of the updating thread.
+///
+/// A delete first seals a data node by swapping its value for null, and only then unlinks it. A
+/// sealed node reads as absent, and since every CAS on the value expects the non-null pointer it
+/// loaded, no update can land on a node once its delete has taken effect. Data nodes have odd
+/// split-order keys, so a sealed node is never mistaken for a sentinel.
+#[derive(Debug)]
+struct Item<V> {
+    key: usize,
+    /// Null for sentinel nodes and sealed data nodes.
+    value: Atomic<V>,
+}
+
+impl<V> Item<V> {
+    fn sentinel(key: usize) -> Self {
+        Self {
+            key,
+            value: Atomic::null(),
+        }
+    }
+
+    fn data(key: usize, value: V) -> Self {
+        Self {
+            key,
+            value: Atomic::new(value),
+        }
+    }
+
+    fn value<'g>(&self, guard: &'g Guard) -> Option<&'g V> {
+        unsafe { self.value.load(Ordering::Acquire, guard).as_ref() }
+    }
+
+    /// Takes back the value of an item that never made it into the list.
+    fn into_value(self) -> Option<V> {
+        unsafe {
+            let value = self
+                .value
+                .swap(Shared::null(), Ordering::Relaxed, unprotected());
+            if value.is_null() {
+                None
+            } else {
+                Some(*value.into_owned().into_box())
+            }
+        }
+    }
+}
+
+impl<V> Drop for Item<V> {
+    fn drop(&mut self) {
+        unsafe {
+            let value = self.value.load(Ordering::Relaxed, unprotected());
+            if !value.is_null() {
+                drop(value.into_owned());
+            }
+        }
+    }
+}
+
+/// Reason `SplitOrderedList::insert_if_bucket_under` refused an insert. Both variants hand the
+/// value back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InsertRejected<V> {
+    /// The key is already present.
+    Occupied(V),
+    /// The bucket the key maps to already holds `max_chain` entries.
+    BucketFull(V),
+}
+
+impl<V> InsertRejected<V> {
+    /// Reports a rejection by an insert without a chain limit, which can only be `Occupied`.
+    fn into_key_exists(self) -> MapError<V> {
+        match self {
+            InsertRejected::Occupied(value) | InsertRejected::BucketFull(value) => {
+                MapError::KeyExists(value)
+            }
+        }
+    }
+}
+
+/// Reason `SplitOrderedList::insert` or `SplitOrderedList::delete` failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MapError<V> {
+    /// The key is already present. Holds the value that was not inserted.
+    KeyExists(V),
+    /// The key is absent.
+    KeyNotFound,
+}
+
+impl<V> MapError<V> {
+    /// Returns the value rejected by `insert`, if any.
+    pub fn into_value(self) -> Option<V> {
+        match self {
+            MapError::KeyExists(value) => Some(value),
+            MapError::KeyNotFound => None,
+        }
+    }
+}
+
+/// Callback registered with `SplitOrderedList::on_long_chain`.
+struct LongChainHook {
+    threshold: usize,
+    f: Box<dyn Fn(usize, usize) + Send + Sync>,
+}
+
+impl fmt::Debug for LongChainHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LongChainHook")
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+/// Weight function set by `SplitOrderedList::with_weigher`.
+struct Weigher<V> {
+    threshold: usize,
+    f: Box<dyn Fn(&V) -> usize + Send + Sync>,
+}
+
+impl<V> fmt::Debug for Weigher<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Weigher")
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+/// Iterator over the entries of a `SplitOrderedList`, in split order. See
+/// `SplitOrderedList::stable_iter`.
+pub struct StableIter<'g, V> {
+    map: &'g SplitOrderedList<V>,
+    /// `None` once the end of the list is reached.
+    cursor: Option<Cursor<'g, usize, Item<V>>>,
+    guard: &'g Guard,
+}
+
+/// Iterator returned by `SplitOrderedList::iter`.
+pub type Iter<'g, V> = StableIter<'g, V>;
+
+impl<'g, V> Iterator for StableIter<'g, V> {
+    type Item = (usize, &'g V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let cursor = self.cursor.as_mut()?;
+            let item = match cursor.lookup() {
+                Some(item) => item,
+                None => {
+                    self.cursor = None;
+                    return None;
+                }
+            };
+            if !self.map.advance(cursor, item.key, self.guard) {
+                self.cursor = None;
+            }
+            if let Some(value) = item.value(self.guard) {
+                return Some((SplitOrderedList::<V>::user_key(item.key), value));
+            }
+        }
+    }
+}
+
+/// Iterator that deletes the entries of a `SplitOrderedList` as it yields them. See
+/// `SplitOrderedList::drain`.
+pub struct Drain<'a, V> {
+    map: &'a mut SplitOrderedList<V>,
+}
+
+impl<'a, V> Iterator for Drain<'a, V> {
+    type Item = (usize, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, _) = self.map.first(unsafe { unprotected() })?;
+        let value = self.map.remove(&key).unwrap();
+        Some((key, value))
+    }
+}
+
+/// Outcome of `SplitOrderedList::probe`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Probe<'g, V> {
+    /// The key is present with this value.
+    Found(&'g V),
+    /// The key is absent, and its bucket is initialized.
+    Absent,
+    /// The key is absent, and its bucket has no sentinel yet.
+    BucketUninitialized,
+}
+
+/// Entry for a key of a `SplitOrderedList`, returned by `SplitOrderedList::entry`.
+///
+/// The entry is a snapshot taken when it was created. Other threads may insert or delete the key
+/// in the meantime, so an `Occupied` entry can refer to a value that has since been deleted, and
+/// inserting into a `Vacant` one can find the key occupied after all.
 #[derive(Debug)]
+pub enum Entry<'g, V> {
+    Occupied(OccupiedEntry<'g, V>),
+    Vacant(VacantEntry<'g, V>),
+}
+
+/// Entry whose key was present. See `Entry`.
+#[derive(Debug)]
+pub struct OccupiedEntry<'g, V> {
+    key: usize,
+    value: &'g V,
+}
+
+/// Entry whose key was absent. See `Entry`.
+#[derive(Debug)]
+pub struct VacantEntry<'g, V> {
+    map: &'g SplitOrderedList<V>,
+    key: usize,
+    guard: &'g Guard,
+}
+
+impl<'g, V> Entry<'g, V> {
+    pub fn key(&self) -> usize {
+        match self {
+            Entry::Occupied(entry) => entry.key,
+            Entry::Vacant(entry) => entry.key,
+        }
+    }
+
+    /// Returns the value of the entry, inserting `value` if it is vacant.
+    pub fn or_insert(self, value: V) -> &'g V {
+        self.or_insert_with(|| value)
+    }
+
+    /// Returns the value of the entry, inserting `f()` if it is vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'g V {
+        match self {
+            Entry::Occupied(entry) => entry.into_value(),
+            Entry::Vacant(entry) => entry.insert_with(f),
+        }
+    }
+}
+
+impl<'g, V> OccupiedEntry<'g, V> {
+    pub fn key(&self) -> usize {
+        self.key
+    }
+
+    pub fn get(&self) -> &V {
+        self.value
+    }
+
+    /// Returns the value with the lifetime of the guard the entry was created with.
+    pub fn into_value(self) -> &'g V {
+        self.value
+    }
+}
+
+impl<'g, V> VacantEntry<'g, V> {
+    pub fn key(&self) -> usize {
+        self.key
+    }
+
+    /// Inserts `value` and returns it. If another thread inserted the key since the entry was
+    /// created, `value` is dropped and the other thread's value is returned instead.
+    pub fn insert(self, value: V) -> &'g V {
+        self.insert_with(|| value)
+    }
+
+    fn insert_with<F: FnOnce() -> V>(self, f: F) -> &'g V {
+        self.map.get_or_insert_with(&self.key, f, self.guard)
+    }
+}
+
+/// Bucket pointer stored in the bucket array next to the sentinel actually found in the list. See
+/// `SplitOrderedList::debug_bucket`.
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketDebug {
+    /// Pointer stored for the bucket, null if the bucket is uninitialized.
+    pub stored_ptr: *const (),
+    /// Sentinel node for the bucket found by walking the list, null if there is none.
+    pub list_sentinel_ptr: *const (),
+    /// Whether both pointers are equal.
+    pub matches: bool,
+}
+
+/// Lock-free map from `usize` in range [0, 2^(w-1)-1] to `V`, where `w` is the width of `usize`
+/// (2^63-1 on 64-bit targets, 2^31-1 on 32-bit ones).
+///
+/// NOTE: We don't care about hashing in this homework for simplicity.
 pub struct SplitOrderedList<V> {
-    /// Lock-free list sorted by recursive-split order. Use `None` sentinel node value.
-    list: List<usize, Option<V>>,
+    /// Lock-free list sorted by recursive-split order. Sentinel nodes have a null value.
+    list: List<usize, Item<V>>,
     /// array of pointers to the buckets
-    buckets: GrowableArray<Node<usize, Option<V>>>,
-    /// number of buckets
-    size: AtomicUsize,
-    /// number of items
-    count: AtomicUsize,
+    buckets: GrowableArray<Node<usize, Item<V>>>,
+    /// number of buckets. Read by every operation but rarely written, so it gets a cache line of
+    /// its own rather than sharing one with the counters that every insert and delete writes.
+    size: CachePadded<AtomicUsize>,
+    /// number of items, striped to spread concurrent updates
+    count: StripedCounter,
+    /// total weight of the values, only tracked if there is a weigher. Padded for the same reason
+    /// as `size`.
+    weight: CachePadded<AtomicUsize>,
+    /// weigher set by `with_weigher`
+    weigher: Option<Weigher<V>>,
+    /// callback registered with `on_long_chain`
+    long_chain_hook: Option<LongChainHook>,
+    /// upper bound on `size`, set by `max_size_cap`
+    max_size: AtomicUsize,
+    /// `size` is doubled when `count > size * load_factor`
+    load_factor: AtomicUsize,
+    /// `(min, max)` bounds of the load factor if it adapts to the workload
+    #[cfg(feature = "metrics")]
+    adaptive_load_factor: Option<(usize, usize)>,
+    /// number of lookups since construction
+    #[cfg(feature = "metrics")]
+    lookups: AtomicU64,
+    /// number of successful inserts since construction
+    #[cfg(feature = "metrics")]
+    inserts: AtomicU64,
+    /// number of successful deletes since construction
+    #[cfg(feature = "metrics")]
+    deletes: AtomicU64,
+    /// `lookups` when the current window of `adapt_load_factor` started
+    #[cfg(feature = "metrics")]
+    window_lookups: AtomicU64,
+    /// `inserts + deletes` when the current window of `adapt_load_factor` started
+    #[cfg(feature = "metrics")]
+    window_writes: AtomicU64,
 }
 
 impl<V> Default for SplitOrderedList<V> {
@@ -28,43 +356,182 @@ impl<V> Default for SplitOrderedList<V> {
         Self {
             list: List::new(),
             buckets: GrowableArray::new(),
-            size: AtomicUsize::new(2),
-            count: AtomicUsize::new(0),
+            size: CachePadded::new(AtomicUsize::new(2)),
+            count: StripedCounter::default(),
+            weight: CachePadded::new(AtomicUsize::new(0)),
+            weigher: None,
+            long_chain_hook: None,
+            max_size: AtomicUsize::new(usize::MAX),
+            load_factor: AtomicUsize::new(Self::LOAD_FACTOR),
+            #[cfg(feature = "metrics")]
+            adaptive_load_factor: None,
+            #[cfg(feature = "metrics")]
+            lookups: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            inserts: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            deletes: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            window_lookups: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            window_writes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<V> NonblockingMap<usize, V> for SplitOrderedList<V> {
+    fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.lookup(key, guard)
+    }
+
+    fn insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), V> {
+        self.insert(key, value, guard)
+            .map_err(|err| err.into_value().unwrap())
+    }
+
+    fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.delete(key, guard).ok()
+    }
+}
+
+/// Formats the entries as a map from user keys to values, leaving out sentinel nodes. The alternate
+/// form `{:#?}` also shows `size` and `count`.
+impl<V: fmt::Debug> fmt::Debug for SplitOrderedList<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Entries<'a, V>(&'a SplitOrderedList<V>);
+
+        impl<V: fmt::Debug> fmt::Debug for Entries<'_, V> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let guard = crossbeam_epoch::pin();
+                f.debug_map().entries(self.0.iter(&guard)).finish()
+            }
+        }
+
+        if f.alternate() {
+            f.debug_struct("SplitOrderedList")
+                .field("size", &self.size.load(Ordering::Relaxed))
+                .field("count", &self.count.sum())
+                .field("entries", &Entries(self))
+                .finish()
+        } else {
+            Entries(self).fmt(f)
+        }
+    }
+}
+
+/// Copies the entries into a new list, which rebuilds its own sentinels.
+///
+/// The copy is only a consistent snapshot if `self` is quiescent. Entries inserted or deleted
+/// concurrently may or may not be copied. The load factor settings and size cap carry over, but a
+/// weigher or long chain hook can't be cloned and is not.
+impl<V: Clone> Clone for SplitOrderedList<V> {
+    fn clone(&self) -> Self {
+        let guard = crossbeam_epoch::pin();
+        let map = Self {
+            load_factor: AtomicUsize::new(self.load_factor.load(Ordering::Relaxed)),
+            max_size: AtomicUsize::new(self.max_size.load(Ordering::Relaxed)),
+            #[cfg(feature = "metrics")]
+            adaptive_load_factor: self.adaptive_load_factor,
+            ..Self::default()
+        };
+        for (key, value) in self.iter(&guard) {
+            // Keys are unique in `self`
+            let _ = map.insert(&key, value.clone(), &guard);
         }
+        map
+    }
+}
+
+/// Later pairs with the same key overwrite earlier ones, as in `HashMap`.
+impl<V> Extend<(usize, V)> for SplitOrderedList<V> {
+    fn extend<I: IntoIterator<Item = (usize, V)>>(&mut self, iter: I) {
+        let guard = crossbeam_epoch::pin();
+        for (key, value) in iter {
+            self.replace(&key, value, &guard);
+        }
+    }
+}
+
+/// Later pairs with the same key overwrite earlier ones, as in `HashMap`.
+impl<V> FromIterator<(usize, V)> for SplitOrderedList<V> {
+    fn from_iter<I: IntoIterator<Item = (usize, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
     }
 }
 
 impl<V> SplitOrderedList<V> {
-    /// `size` is doubled when `count > size * LOAD_FACTOR`.
+    /// Default load factor. `size` is doubled when `count > size * LOAD_FACTOR`.
     const LOAD_FACTOR: usize = 2;
-    const HI_MASK: usize = 0x8000000000000000; //1 << (mem::size_of::<usize>()*8 - 1)
+    /// Highest bit of `usize`, which user keys must leave clear.
+    const HI_MASK: usize = 1 << (mem::size_of::<usize>() * 8 - 1);
 
     /// Creates a new split ordered list.
     pub fn new() -> Self {
         Self::default()
     }
 
-    fn get_parent(&self, bucket_index: usize) -> usize {
-        let mut parent: usize = self.size.load(Ordering::Acquire);
-        loop {
-            parent = parent >> 1;
-            if (parent <= bucket_index) {
-                break;
-            }
+    /// Creates a map with enough buckets for `n` entries at the default load factor, so that
+    /// inserting them doesn't resize. Buckets are still initialized lazily on first use.
+    pub fn with_capacity(n: usize) -> Self {
+        let size = (n / Self::LOAD_FACTOR + 1).next_power_of_two();
+        Self {
+            size: CachePadded::new(AtomicUsize::new(cmp::max(size, 2))),
+            ..Self::default()
+        }
+    }
+
+    /// Creates a map whose `size` is doubled when `count > size * load_factor` instead of the
+    /// default `LOAD_FACTOR`. Panics if `load_factor` is 0.
+    pub fn with_load_factor(load_factor: usize) -> Self {
+        assert!(load_factor != 0, "load factor must be positive");
+        Self {
+            load_factor: AtomicUsize::new(load_factor),
+            ..Self::default()
+        }
+    }
+
+    /// Creates a map that resizes on the total weight of its values instead of their number:
+    /// `size` is doubled when the weight per bucket exceeds `threshold`, e.g. a number of bytes.
+    ///
+    /// A deleted value subtracts `weigher(value)` again, so `weigher` must return the same weight
+    /// for a value every time it is called on it.
+    pub fn with_weigher<F>(threshold: usize, weigher: F) -> Self
+    where
+        F: Fn(&V) -> usize + Send + Sync + 'static,
+    {
+        Self {
+            weigher: Some(Weigher {
+                threshold,
+                f: Box::new(weigher),
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the parent of the given bucket: its index with the most significant set bit
+    /// cleared, as in the paper. Bucket 0 is the root and its own parent.
+    ///
+    /// The parent depends on the index alone, not on `size`, so a bucket at or above a `size`
+    /// that a concurrent `shrink` or `clear` just lowered still gets its proper parent.
+    fn get_parent(bucket_index: usize) -> usize {
+        if bucket_index == 0 {
+            return 0;
         }
-        return bucket_index - parent;
+        bucket_index & !(Self::HI_MASK >> bucket_index.leading_zeros())
     }
 
-    fn make_sentinel<'s>(&self, parent_index: usize, child_index: usize, guard: &'s Guard) {
+    fn make_sentinel<'s>(&'s self, parent_index: usize, child_index: usize, guard: &'s Guard) {
         let key = child_index.reverse_bits();
-        let mut owned = Owned::new(Node::new(key, None));
-        let parent = self.buckets.get(parent_index, guard);
+        let mut owned = Owned::new(Node::new(key, Item::sentinel(key)));
         loop {
-            let mut cursor =
-                unsafe { Cursor::from_raw(parent, parent.load(Ordering::Acquire, guard).as_raw()) };
+            let mut cursor = self.bucket_cursor(parent_index, guard);
             let res = Cursor::find_harris_michael(&mut cursor, &key, guard);
             if let Ok(found) = res {
                 if (found) {
+                    // Linked by another thread, which stores it in the bucket itself. Storing it
+                    // here as well could bring back a sentinel that `shrink` is retiring
                     return;
                 } else {
                     match Cursor::insert(&mut cursor, owned, guard) {
@@ -81,15 +548,19 @@ impl<V> SplitOrderedList<V> {
         }
     }
 
-    fn initialize_bucket<'s>(&'s self, bucket_index: usize, guard: &'s Guard) {
-        let mut current = self
-            .buckets
-            .get(bucket_index, guard)
-            .load(Ordering::Acquire, guard);
-        if (bucket_index == 0 && current.is_null()) {
-            if self.list.harris_herlihy_shavit_insert(0, None, guard) {
+    fn initialize_first_bucket<'s>(&'s self, guard: &'s Guard) {
+        loop {
+            let current = self.buckets.get(0, guard).load(Ordering::Acquire, guard);
+            if !current.is_null() {
+                return;
+            }
+            if self
+                .list
+                .harris_herlihy_shavit_insert(0, Item::sentinel(0), guard)
+            {
                 let cursor = self.list.head(guard);
-                self.buckets.get(0, guard).compare_and_set(
+                let _ = self.buckets.compare_exchange(
+                    0,
                     current,
                     cursor.curr(),
                     Ordering::AcqRel,
@@ -97,22 +568,39 @@ impl<V> SplitOrderedList<V> {
                 );
             }
         }
+    }
 
-        let parent_index: usize = self.get_parent(bucket_index);
-        let mut parent = self
-            .buckets
-            .get(parent_index, guard)
-            .load(Ordering::Acquire, guard);
-        if (parent.is_null()) {
-            self.initialize_bucket(parent_index, guard);
+    /// Initializes the bucket along with all its uninitialized ancestors. The chain of ancestors
+    /// is collected first and then initialized from the root down, so that a long chain doesn't
+    /// recurse deeply.
+    fn initialize_bucket<'s>(&'s self, bucket_index: usize, guard: &'s Guard) {
+        // (parent, child) pairs, the uninitialized bucket closest to the root last
+        let mut chain = Vec::new();
+        let mut index = bucket_index;
+        while index != 0 {
+            let parent_index: usize = Self::get_parent(index);
+            chain.push((parent_index, index));
+            let parent = self
+                .buckets
+                .get(parent_index, guard)
+                .load(Ordering::Acquire, guard);
+            if !parent.is_null() {
+                break;
+            }
+            index = parent_index;
+        }
+        if index == 0 {
+            self.initialize_first_bucket(guard);
         }
 
-        self.make_sentinel(parent_index, bucket_index, guard);
+        while let Some((parent_index, child_index)) = chain.pop() {
+            self.make_sentinel(parent_index, child_index, guard);
+        }
     }
 
     /// Creates a cursor and moves it to the bucket for the given index.  If the bucket doesn't
     /// exist, recursively initializes the buckets.
-    fn lookup_bucket<'s>(&'s self, index: usize, guard: &'s Guard) -> Cursor<'s, usize, Option<V>> {
+    fn lookup_bucket<'s>(&'s self, index: usize, guard: &'s Guard) -> Cursor<'s, usize, Item<V>> {
         let pointer = self
             .buckets
             .get(index, guard)
@@ -120,10 +608,34 @@ impl<V> SplitOrderedList<V> {
         if (pointer.is_null()) {
             self.initialize_bucket(index, guard);
         }
-        let pointer = self.buckets.get(index, guard);
-        let mut cursor =
-            unsafe { Cursor::from_raw(pointer, pointer.load(Ordering::Acquire, guard).as_raw()) };
-        return cursor;
+        self.bucket_cursor(index, guard)
+    }
+
+    /// Creates a cursor at the sentinel of the given bucket, or of its closest ancestor if the
+    /// bucket holds none. The keys of a bucket come after the sentinels of all its ancestors in
+    /// split order, so they are still found from there.
+    ///
+    /// A bucket can be null even right after `initialize_bucket`: the thread that linked its
+    /// sentinel may not have stored it yet, or `shrink` may have just retired it. A cursor must
+    /// never start from a null bucket, since an insert through it would overwrite the bucket
+    /// instead of linking the node into the list.
+    fn bucket_cursor<'s>(
+        &'s self,
+        mut index: usize,
+        guard: &'s Guard,
+    ) -> Cursor<'s, usize, Item<V>> {
+        loop {
+            let bucket = self.buckets.get(index, guard);
+            let sentinel = bucket.load(Ordering::Acquire, guard);
+            if !sentinel.is_null() {
+                return unsafe { Cursor::from_raw(bucket, sentinel.as_raw()) };
+            }
+            if index == 0 {
+                self.initialize_first_bucket(guard);
+            } else {
+                index = Self::get_parent(index);
+            }
+        }
     }
 
     /// Moves the bucket cursor returned from `lookup_bucket` to the position of the given key.
@@ -132,7 +644,7 @@ impl<V> SplitOrderedList<V> {
         &'s self,
         key: &usize,
         guard: &'s Guard,
-    ) -> (usize, bool, Cursor<'s, usize, Option<V>>) {
+    ) -> (usize, bool, Cursor<'s, usize, Item<V>>) {
         let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
 
         loop {
@@ -140,77 +652,2763 @@ impl<V> SplitOrderedList<V> {
             let mut cursor = self.lookup_bucket((*key) % size, guard);
             let res = Cursor::find_harris_michael(&mut cursor, &ordinary_key, guard);
             if let Ok(found) = res {
+                if found && cursor.lookup().unwrap().value(guard).is_none() {
+                    // Sealed by a delete that hasn't unlinked it yet, so help
+                    let _ = cursor.delete(guard);
+                    continue;
+                }
                 return (size, found, cursor);
             }
         }
     }
 
-    fn assert_valid_key(key: usize) {
-        assert!(key.leading_zeros() != 0);
-    }
-}
-
-impl<V> SplitOrderedList<V> {
-    pub fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
-        Self::assert_valid_key(*key);
+    /// Like `find`, but never initializes buckets or allocates segments, for read-only operations.
+    /// Returns a cursor at the key if it is present.
+    ///
+    /// An uninitialized bucket is searched from its closest initialized ancestor instead. The keys
+    /// of a bucket come after the sentinels of all its ancestors in split order, so they are still
+    /// found. If not even bucket 0 exists, nothing was ever inserted.
+    fn find_readonly<'s>(
+        &'s self,
+        key: &usize,
+        guard: &'s Guard,
+    ) -> Option<Cursor<'s, usize, Item<V>>> {
+        let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
+        let reader = self.buckets.reader(guard);
 
-        let (size, found, cursor) = self.find(key, guard);
-        if found {
-            match cursor.lookup().as_ref().map(|n| (**n).as_ref()) {
-                Some(opt) => {
-                    return opt;
+        loop {
+            let size: usize = self.size.load(Ordering::Acquire);
+            let mut index = *key % size;
+            let (bucket, sentinel) = loop {
+                if let Some(bucket) = reader.try_get(index) {
+                    let sentinel = bucket.load(Ordering::Acquire, guard);
+                    if !sentinel.is_null() {
+                        break (bucket, sentinel);
+                    }
                 }
-                None => {
+                if index == 0 {
                     return None;
                 }
+                index = Self::get_parent(index);
+            };
+            let mut cursor = unsafe { Cursor::from_raw(bucket, sentinel.as_raw()) };
+            if let Ok(found) = Cursor::find_harris_michael(&mut cursor, &ordinary_key, guard) {
+                // A sealed node is already deleted
+                let live = found && cursor.lookup().unwrap().value(guard).is_some();
+                return if live { Some(cursor) } else { None };
             }
-        } else {
-            return None;
         }
     }
 
-    pub fn insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), V> {
-        Self::assert_valid_key(*key);
+    pub(crate) fn assert_valid_key(key: usize) {
+        assert!(key & Self::HI_MASK == 0);
+    }
 
-        let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
-        let mut owned = Owned::new(Node::new(ordinary_key, Some(value)));
-        loop {
-            let (size, found, mut cursor) = self.find(key, guard);
-            if (found) {
-                let val = owned.into_box().into_value();
-                return Err(val.unwrap());
+    /// Recovers the user-facing key from the split-order key of a data node.
+    fn user_key(key: usize) -> usize {
+        key.reverse_bits() & !SplitOrderedList::<V>::HI_MASK
+    }
+
+    /// Moves the cursor from the node with split-order key `key` to the node after it. Returns
+    /// `false` if `key` is the largest possible key, leaving the cursor untouched.
+    fn advance<'g>(
+        &'g self,
+        cursor: &mut Cursor<'g, usize, Item<V>>,
+        key: usize,
+        guard: &'g Guard,
+    ) -> bool {
+        let next = match key.checked_add(1) {
+            Some(next) => next,
+            None => return false,
+        };
+        while Cursor::find_harris_michael(cursor, &next, guard).is_err() {
+            *cursor = self.list.head(guard);
+        }
+        true
+    }
+
+    /// Calls `f` with the key and value of every data node, in split order.
+    pub(crate) fn for_each<'g, F: FnMut(usize, &'g V)>(&'g self, mut f: F, guard: &'g Guard) {
+        for (key, value) in self.stable_iter(guard) {
+            f(key, value);
+        }
+    }
+
+    /// Counts the data nodes between the sentinel of the given bucket and the next sentinel, only
+    /// counting those whose split-order key is below `until`.
+    fn chain_len(&self, bucket_index: usize, until: usize, guard: &Guard) -> usize {
+        let mut cursor = self.lookup_bucket(bucket_index, guard);
+        let mut len = 0;
+        while let Some(item) = cursor.lookup() {
+            if item.key >= until && item.key != bucket_index.reverse_bits() {
+                break;
             }
-            match cursor.insert(owned, guard) {
-                Err(n) => owned = n,
-                Ok(()) => {
-                    break;
+            if item.key & 1 == 1 {
+                // Sealed nodes are already deleted
+                if item.value(guard).is_some() {
+                    len += 1;
                 }
+            } else if item.key != bucket_index.reverse_bits() {
+                break;
+            }
+            if !self.advance(&mut cursor, item.key, guard) {
+                break;
             }
         }
+        len
+    }
 
-        let count = self.count.fetch_add(1, Ordering::AcqRel);
-        let size = self.size.load(Ordering::Acquire);
-        if (count / size > SplitOrderedList::<V>::LOAD_FACTOR) {
-            self.size.compare_and_swap(size, size * 2, Ordering::AcqRel);
+    /// Bookkeeping after a new entry was linked into the given bucket. `maybe_resize` is skipped
+    /// unless `resize` is set.
+    fn after_insert(
+        &self,
+        bucket_index: usize,
+        ordinary_key: usize,
+        weight: usize,
+        resize: bool,
+        guard: &Guard,
+    ) {
+        self.increment_count(weight);
+        // Summing the counter on every insert would have all inserting threads read every stripe,
+        // so the estimate rules most of them out first
+        if resize && self.may_be_overloaded() {
+            self.maybe_resize();
+        }
+        if let Some(hook) = self.long_chain_hook.as_ref() {
+            let len = self.chain_len(bucket_index, ordinary_key, guard);
+            if len > hook.threshold {
+                (hook.f)(bucket_index, len);
+            }
         }
-        return Ok(());
     }
 
-    pub fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Result<&'a V, ()> {
-        Self::assert_valid_key(*key);
+    /// Weight of `value` according to the weigher, 0 if there is none.
+    fn weigh(&self, value: &V) -> usize {
+        self.weigher
+            .as_ref()
+            .map_or(0, |weigher| (weigher.f)(value))
+    }
 
-        loop {
-            let (size, found, cursor) = self.find(key, guard);
-            if !found {
-                return Err(());
+    /// Increments `count` and `weight` after an insert.
+    fn increment_count(&self, weight: usize) {
+        #[cfg(feature = "metrics")]
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        self.adapt_load_factor();
+
+        self.count.increment();
+        self.weight.fetch_add(weight, Ordering::AcqRel);
+    }
+
+    /// Cheap check ahead of `maybe_resize` that only reads the counter stripe of the calling
+    /// thread. The thread with the fullest stripe always sees the load factor exceeded once it is,
+    /// so the map still grows, if a few inserts late.
+    fn may_be_overloaded(&self) -> bool {
+        if self.weigher.is_some() {
+            return true;
+        }
+        let size = self.size.load(Ordering::Acquire);
+        self.count.estimate() > size.saturating_mul(self.load_factor.load(Ordering::Relaxed))
+    }
+
+    /// Doubles `size` if the load factor, or the weight threshold if there is a weigher, is
+    /// exceeded.
+    ///
+    /// `insert` calls this after every insert. `try_insert` leaves it to the caller, e.g. a
+    /// background task that calls it periodically.
+    pub fn maybe_resize(&self) {
+        let size = self.size.load(Ordering::Acquire);
+        let overloaded = match self.weigher.as_ref() {
+            Some(weigher) => {
+                self.weight.load(Ordering::Acquire) > size.saturating_mul(weigher.threshold)
             }
-            match cursor.delete(guard) {
-                Err(()) => continue,
-                Ok(value) => {
-                    self.count.fetch_sub(1, Ordering::AcqRel);
-                    return value.as_ref().ok_or(());
-                }
+            None => {
+                self.count.sum() > size.saturating_mul(self.load_factor.load(Ordering::Relaxed))
             }
+        };
+        if overloaded && size * 2 <= self.max_size.load(Ordering::Relaxed) {
+            // Fails if another thread already doubled `size`, which then needs no second doubling
+            let _ = self
+                .size
+                .compare_exchange(size, size * 2, Ordering::AcqRel, Ordering::Acquire);
+        }
+    }
+}
+
+impl<V> SplitOrderedList<V> {
+    pub fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        Self::assert_valid_key(*key);
+        #[cfg(feature = "metrics")]
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+
+        let cursor = self.find_readonly(key, guard)?;
+        cursor.lookup().and_then(|n| n.value(guard))
+    }
+
+    /// Looks up `key` like `lookup`, and for a miss also tells whether the bucket of the key is
+    /// initialized, i.e. whether that region of the table is cold. Neither initializes buckets nor
+    /// allocates.
+    ///
+    /// A present key is always `Found`, even if its own bucket is uninitialized and it was found
+    /// from an ancestor bucket. The bucket is checked for the `size` read at the start, which a
+    /// concurrent resize may change.
+    pub fn probe<'g>(&'g self, key: &usize, guard: &'g Guard) -> Probe<'g, V> {
+        let size = self.size.load(Ordering::Acquire);
+        let initialized = !self.buckets.load(*key % size, guard).is_null();
+        match self.lookup(key, guard) {
+            Some(value) => Probe::Found(value),
+            None if initialized => Probe::Absent,
+            None => Probe::BucketUninitialized,
+        }
+    }
+
+    /// Looks up every key of `keys` within the epoch pinned by `guard`, returning the results in
+    /// the same order. A key given twice yields the same reference twice.
+    pub fn get_many<'g, const N: usize>(
+        &'g self,
+        keys: &[usize; N],
+        guard: &'g Guard,
+    ) -> [Option<&'g V>; N] {
+        keys.map(|key| self.lookup(&key, guard))
+    }
+
+    /// Returns the value for `key`, or `default` if the key is absent.
+    pub fn lookup_or<'a>(&'a self, key: &usize, default: &'a V, guard: &'a Guard) -> &'a V {
+        self.lookup(key, guard).unwrap_or(default)
+    }
+
+    /// Returns a clone of the value for `key`, which unlike `lookup` does not borrow from `guard`.
+    pub fn get_cloned(&self, key: &usize, guard: &Guard) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.lookup(key, guard).cloned()
+    }
+
+    /// Returns whether `key` is present, without touching its value.
+    pub fn contains_key(&self, key: &usize, guard: &Guard) -> bool {
+        Self::assert_valid_key(*key);
+        #[cfg(feature = "metrics")]
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+
+        self.find_readonly(key, guard).is_some()
+    }
+
+    /// Inserts `value` for `key`, or fails with `MapError::KeyExists` holding `value` if the key
+    /// is already present.
+    pub fn insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), MapError<V>> {
+        self.insert_impl(key, value, true, usize::MAX, guard)
+            .map_err(InsertRejected::into_key_exists)
+    }
+
+    /// Same as `insert`, but never resizes. Growth is left to `maybe_resize`, which keeps the
+    /// `size` CAS off the insert path.
+    pub fn try_insert(&self, key: &usize, value: V, guard: &Guard) -> Result<(), MapError<V>> {
+        self.insert_impl(key, value, false, usize::MAX, guard)
+            .map_err(InsertRejected::into_key_exists)
+    }
+
+    /// Inserts unless the key is present or its bucket already holds `max_chain` entries. The
+    /// chain is only counted for a finite `max_chain`.
+    fn insert_impl(
+        &self,
+        key: &usize,
+        value: V,
+        resize: bool,
+        max_chain: usize,
+        guard: &Guard,
+    ) -> Result<(), InsertRejected<V>> {
+        Self::assert_valid_key(*key);
+
+        let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
+        let weight = self.weigh(&value);
+        // The node is only allocated once the key is known to be absent, and is reused if the
+        // insert has to be retried
+        let mut value = Some(value);
+        let mut node: Option<Owned<Node<usize, Item<V>>>> = None;
+        let bucket_index = loop {
+            let (size, found, mut cursor) = self.find(key, guard);
+            let full = !found
+                && max_chain != usize::MAX
+                && self.chain_len(*key % size, usize::MAX, guard) >= max_chain;
+            if found || full {
+                let value = match node {
+                    // Moving the node out of its box frees the allocation
+                    Some(node) => node.into_box().into_value().into_value(),
+                    None => value,
+                };
+                let value = value.unwrap();
+                return Err(if found {
+                    InsertRejected::Occupied(value)
+                } else {
+                    InsertRejected::BucketFull(value)
+                });
+            }
+            let owned = node.take().unwrap_or_else(|| {
+                let item = Item::data(ordinary_key, value.take().unwrap());
+                Owned::new(Node::new(ordinary_key, item))
+            });
+            match cursor.insert(owned, guard) {
+                Err(n) => node = Some(n),
+                Ok(()) => {
+                    break *key % size;
+                }
+            }
+        };
+
+        self.after_insert(bucket_index, ordinary_key, weight, resize, guard);
+        return Ok(());
+    }
+
+    /// Inserts `value` for `key`, replacing the current value if the key is present. Returns a
+    /// clone of the replaced value.
+    ///
+    /// The replaced value is retired through `guard` like in `update`. The replacement is a CAS on
+    /// the value of the live node, so it never lands on a node a concurrent delete has sealed:
+    /// the key is looked up again and inserted anew instead.
+    pub fn insert_or_replace(&self, key: &usize, value: V, guard: &Guard) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.replace(key, value, guard).cloned()
+    }
+
+    /// Same as `insert_or_replace`, but returns the replaced value as retired through `guard`.
+    fn replace<'g>(&'g self, key: &usize, value: V, guard: &'g Guard) -> Option<&'g V> {
+        Self::assert_valid_key(*key);
+
+        let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
+        let weight = self.weigh(&value);
+        // Allocated lazily like in `insert`
+        let mut value = Some(value);
+        let mut node: Option<Owned<Node<usize, Item<V>>>> = None;
+        let bucket_index = loop {
+            let (size, found, mut cursor) = self.find(key, guard);
+            if found {
+                let item = cursor.lookup().unwrap();
+                let current = item.value.load(Ordering::Acquire, guard);
+                let old = match unsafe { current.as_ref() } {
+                    Some(old) => old,
+                    None => continue,
+                };
+                let new = match node.take() {
+                    Some(node) => node.into_box().into_value().into_value().unwrap(),
+                    None => value.take().unwrap(),
+                };
+                match item
+                    .value
+                    .compare_and_set(current, Owned::new(new), Ordering::AcqRel, guard)
+                {
+                    Ok(_) => {
+                        self.weight.fetch_add(weight, Ordering::AcqRel);
+                        self.weight.fetch_sub(self.weigh(old), Ordering::AcqRel);
+                        unsafe { guard.defer_destroy(current) };
+                        return Some(old);
+                    }
+                    Err(e) => {
+                        value = Some(*e.new.into_box());
+                        continue;
+                    }
+                }
+            }
+            let owned = node.take().unwrap_or_else(|| {
+                let item = Item::data(ordinary_key, value.take().unwrap());
+                Owned::new(Node::new(ordinary_key, item))
+            });
+            match cursor.insert(owned, guard) {
+                Err(n) => node = Some(n),
+                Ok(()) => break *key % size,
+            }
+        };
+
+        self.after_insert(bucket_index, ordinary_key, weight, true, guard);
+        None
+    }
+
+    /// Returns the entry for `key`, to be inspected or filled in. See `Entry`.
+    pub fn entry<'g>(&'g self, key: usize, guard: &'g Guard) -> Entry<'g, V> {
+        match self.lookup(&key, guard) {
+            Some(value) => Entry::Occupied(OccupiedEntry { key, value }),
+            None => Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                guard,
+            }),
+        }
+    }
+
+    /// Returns the value for `key`, inserting `f()` first if the key is absent.
+    ///
+    /// `f` is only called if the key is absent on the first lookup. If another thread inserts the
+    /// key before the new entry is linked, the other thread's value is returned and the one built
+    /// by `f` is dropped.
+    pub fn get_or_insert_with<'a, F: FnOnce() -> V>(
+        &'a self,
+        key: &usize,
+        f: F,
+        guard: &'a Guard,
+    ) -> &'a V {
+        Self::assert_valid_key(*key);
+
+        let ordinary_key = (*key | SplitOrderedList::<V>::HI_MASK).reverse_bits();
+        let mut f = Some(f);
+        let mut node: Option<Owned<Node<usize, Item<V>>>> = None;
+        // Value of `node`, kept aside as a concurrent delete may seal the node right after it is
+        // linked. The value outlives `guard` either way
+        let mut value = Shared::null();
+        loop {
+            let (size, found, mut cursor) = self.find(key, guard);
+            if found {
+                // Sealed by a concurrent delete if `None`
+                match cursor.lookup().and_then(|item| item.value(guard)) {
+                    Some(value) => return value,
+                    None => continue,
+                }
+            }
+            let owned = node.take().unwrap_or_else(|| {
+                let item = Item::data(ordinary_key, f.take().unwrap()());
+                value = item.value.load(Ordering::Relaxed, guard);
+                Owned::new(Node::new(ordinary_key, item))
+            });
+            let weight = self.weigh(unsafe { value.deref() });
+            match cursor.insert(owned, guard) {
+                Err(n) => node = Some(n),
+                Ok(()) => {
+                    self.after_insert(*key % size, ordinary_key, weight, true, guard);
+                    return unsafe { value.deref() };
+                }
+            }
+        }
+    }
+
+    /// Inserts only if the bucket the key maps to holds fewer than `max_chain` entries.
+    ///
+    /// The chain length is counted right before the insertion, so entries concurrently inserted
+    /// into the same bucket can push it past `max_chain`, and a resize can leave it shorter than
+    /// counted.
+    pub fn insert_if_bucket_under(
+        &self,
+        key: &usize,
+        value: V,
+        max_chain: usize,
+        guard: &Guard,
+    ) -> Result<(), InsertRejected<V>> {
+        self.insert_impl(key, value, true, max_chain, guard)
+    }
+
+    /// Deletes the entry for `key` and returns its value, or fails with `MapError::KeyNotFound`.
+    pub fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Result<&'a V, MapError<V>> {
+        self.delete_if(key, |_| true, guard)
+            .map_err(|()| MapError::KeyNotFound)
+    }
+
+    /// Deletes the entry for `key` and moves its value out.
+    ///
+    /// A value deleted through `&self` has to be retired through the epoch, as threads that found
+    /// it before the deletion may still read it until their guards are dropped. Handing it out by
+    /// value would let the caller drop what they are reading. This takes `&mut self` instead: no
+    /// other thread can then hold a reference to the value, so it is taken out of the sealed node
+    /// before the node is freed, without an epoch in between. `delete` is the concurrent
+    /// counterpart.
+    pub fn remove(&mut self, key: &usize) -> Option<V> {
+        unsafe {
+            let value = self.unlink_if(key, |_| true, unprotected()).ok()?;
+            Some(*value.into_owned().into_box())
+        }
+    }
+
+    /// Like `remove`, but also returns whether the map is now underloaded enough for `shrink` to
+    /// halve `size`, as decided from the counters right after the delete.
+    pub fn delete_and_check(&mut self, key: &usize) -> Result<(V, bool), MapError<V>> {
+        let value = self.remove(key).ok_or(MapError::KeyNotFound)?;
+        Ok((value, self.underloaded(self.size.load(Ordering::Acquire))))
+    }
+
+    /// Replaces the value for `key` with `f(old)`, or returns `MapError::KeyNotFound` if the key is
+    /// absent.
+    ///
+    /// The new value is swapped in with a CAS, so `f` is called again with the current value if a
+    /// concurrent update intervened. The old value is retired through `guard`, so references to it
+    /// stay valid for readers that loaded it before. The CAS fails on a node sealed by a
+    /// concurrent delete, in which case the key is looked up again.
+    pub fn update<F: FnMut(&V) -> V>(
+        &self,
+        key: &usize,
+        mut f: F,
+        guard: &Guard,
+    ) -> Result<(), MapError<V>> {
+        Self::assert_valid_key(*key);
+
+        loop {
+            let (_, found, cursor) = self.find(key, guard);
+            if !found {
+                return Err(MapError::KeyNotFound);
+            }
+            let item = cursor.lookup().unwrap();
+            let mut current = item.value.load(Ordering::Acquire, guard);
+            while let Some(old) = unsafe { current.as_ref() } {
+                let new = f(old);
+                let new_weight = self.weigh(&new);
+                match item
+                    .value
+                    .compare_and_set(current, Owned::new(new), Ordering::AcqRel, guard)
+                {
+                    Ok(_) => {
+                        self.weight.fetch_add(new_weight, Ordering::AcqRel);
+                        self.weight.fetch_sub(self.weigh(old), Ordering::AcqRel);
+                        unsafe { guard.defer_destroy(current) };
+                        return Ok(());
+                    }
+                    Err(e) => current = e.current,
+                }
+            }
+        }
+    }
+
+    /// Inserts, replaces or deletes the entry for `key` depending on `f(current)`: `Some(new)`
+    /// becomes the value, while `None` deletes the entry if there is one.
+    ///
+    /// Replacements are swapped in with a CAS and deletions seal the node with a CAS, both from the
+    /// value `f` saw, so `f` is called again with the current value whenever a concurrent change
+    /// intervened, including a delete of the key.
+    pub fn compute<F: FnMut(Option<&V>) -> Option<V>>(&self, key: &usize, mut f: F, guard: &Guard) {
+        Self::assert_valid_key(*key);
+
+        loop {
+            let (_, found, cursor) = self.find(key, guard);
+            if !found {
+                match f(None) {
+                    None => return,
+                    Some(value) => match self.insert(key, value, guard) {
+                        Ok(()) => return,
+                        Err(_) => continue,
+                    },
+                }
+            }
+
+            let item = cursor.lookup().unwrap();
+            let current = item.value.load(Ordering::Acquire, guard);
+            let old = match unsafe { current.as_ref() } {
+                Some(old) => old,
+                None => continue,
+            };
+            match f(Some(old)) {
+                None => {
+                    if self
+                        .delete_if(key, |value| ptr::eq(value, old), guard)
+                        .is_ok()
+                    {
+                        return;
+                    }
+                }
+                Some(new) => {
+                    let new_weight = self.weigh(&new);
+                    if item
+                        .value
+                        .compare_and_set(current, Owned::new(new), Ordering::AcqRel, guard)
+                        .is_ok()
+                    {
+                        self.weight.fetch_add(new_weight, Ordering::AcqRel);
+                        self.weight.fetch_sub(self.weigh(old), Ordering::AcqRel);
+                        unsafe { guard.defer_destroy(current) };
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deletes the entry for `key` only if `pred` holds for its value.
+    ///
+    /// The node is sealed with a CAS from the value `pred` was checked on, so neither an entry
+    /// concurrently re-inserted under the same key nor a value concurrently swapped in by an
+    /// update is ever removed by mistake: `pred` is checked again on the new value instead.
+    pub(crate) fn delete_if<'a, F: FnMut(&V) -> bool>(
+        &'a self,
+        key: &usize,
+        pred: F,
+        guard: &'a Guard,
+    ) -> Result<&'a V, ()> {
+        let value = self.unlink_if(key, pred, guard)?;
+        // The node no longer owns the value
+        unsafe { guard.defer_destroy(value) };
+        Ok(unsafe { value.deref() })
+    }
+
+    /// Seals and unlinks the node for `key` like `delete_if`, and returns the value it held. The
+    /// caller owns the value from then on, and has to retire or take it.
+    fn unlink_if<'a, F: FnMut(&V) -> bool>(
+        &'a self,
+        key: &usize,
+        mut pred: F,
+        guard: &'a Guard,
+    ) -> Result<Shared<'a, V>, ()> {
+        Self::assert_valid_key(*key);
+
+        loop {
+            let (_, found, cursor) = self.find(key, guard);
+            if !found {
+                return Err(());
+            }
+            let item = cursor.lookup().unwrap();
+            let current = item.value.load(Ordering::Acquire, guard);
+            let value = match unsafe { current.as_ref() } {
+                Some(value) => value,
+                None => continue,
+            };
+            if !pred(value) {
+                return Err(());
+            }
+            if item
+                .value
+                .compare_and_set(current, Shared::null(), Ordering::AcqRel, guard)
+                .is_err()
+            {
+                continue;
+            }
+
+            // The entry is gone from here on. Unlinking only fails if a `find` helped first
+            let _ = cursor.delete(guard);
+            self.count.decrement();
+            self.weight.fetch_sub(self.weigh(value), Ordering::AcqRel);
+            #[cfg(feature = "metrics")]
+            self.deletes.fetch_add(1, Ordering::Relaxed);
+            return Ok(current);
+        }
+    }
+
+    /// Halves `size` while the map is less than half as full as the load factor allows, i.e. while
+    /// `count * load_factor * 2 < size`, but not below 2.
+    ///
+    /// Each halving retires the sentinels of the upper half of the buckets, see `retire_bucket`.
+    /// The segments of the bucket array are kept, as a thread that read the larger `size` may
+    /// still be about to store a new sentinel into them.
+    pub fn shrink(&self, guard: &Guard) {
+        loop {
+            let size = self.size.load(Ordering::Acquire);
+            if !self.underloaded(size) {
+                return;
+            }
+            if self
+                .size
+                .compare_exchange(size, size / 2, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                for index in size / 2..size {
+                    self.retire_bucket(index, guard);
+                }
+            }
+        }
+    }
+
+    /// Nulls the given bucket and unlinks its sentinel, which is freed through `guard` once no
+    /// thread can still hold it.
+    ///
+    /// The bucket is nulled first, so a thread loading it from then on starts from an ancestor
+    /// instead, see `bucket_cursor`. A thread that loaded the sentinel before is still pinned, and
+    /// finds it marked as deleted. Only the thread that nulled the bucket unlinks the sentinel.
+    fn retire_bucket(&self, index: usize, guard: &Guard) {
+        let bucket = match self.buckets.reader(guard).try_get(index) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        let sentinel = bucket.swap(Shared::null(), Ordering::AcqRel, guard);
+        if sentinel.is_null() {
+            return;
+        }
+        let key = index.reverse_bits();
+        loop {
+            let mut cursor = self.bucket_cursor(Self::get_parent(index), guard);
+            if let Ok(found) = Cursor::find_harris_michael(&mut cursor, &key, guard) {
+                debug_assert!(found && cursor.curr() == sentinel);
+                // Unlinking is left to later traversals if it fails, and the node is retired by
+                // whichever thread unlinks it
+                let _ = cursor.delete(guard);
+                return;
+            }
+        }
+    }
+
+    /// Whether `shrink` would halve `size`.
+    fn underloaded(&self, size: usize) -> bool {
+        let load_factor = self.load_factor.load(Ordering::Relaxed);
+        size > 2
+            && self
+                .count
+                .sum()
+                .saturating_mul(load_factor)
+                .saturating_mul(2)
+                < size
+    }
+
+    /// Doubles `size` until `additional` more entries fit at the load factor without a resize, as
+    /// in `with_capacity`, but not past `max_size_cap`. A `size` that is already large enough is
+    /// left as is.
+    ///
+    /// Each doubling is the same CAS an insert would do, so concurrent inserts and resizes stay
+    /// correct, and the new buckets are still initialized lazily on first use. Unlike `shrink`, it
+    /// takes no guard, as it only touches `size` and never loads a bucket or a node.
+    pub fn reserve(&self, additional: usize) {
+        let load_factor = self.load_factor.load(Ordering::Relaxed);
+        let target = (self.len().saturating_add(additional) / load_factor + 1)
+            .checked_next_power_of_two()
+            .unwrap_or(usize::MAX);
+        let target = cmp::min(target, self.max_size.load(Ordering::Relaxed));
+        let mut size = self.size.load(Ordering::Acquire);
+        while size < target {
+            size = match self.size.compare_exchange(
+                size,
+                size * 2,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => size * 2,
+                Err(current) => current,
+            };
+        }
+    }
+
+    /// Deletes every entry for which `f` returns `false`.
+    ///
+    /// An entry is only deleted by sealing it with a CAS from the very value `f` was called on, so
+    /// an entry that another thread deleted or replaced in the meantime is left alone. Entries
+    /// inserted concurrently may or may not be visited.
+    pub fn retain<F: FnMut(&usize, &V) -> bool>(&self, mut f: F, guard: &Guard) {
+        for (key, value) in self.iter(guard) {
+            if !f(&key, value) {
+                let _ = self.delete_if(&key, |other| ptr::eq(other, value), guard);
+            }
+        }
+    }
+
+    /// Deletes every entry and shrinks `size` back to 2.
+    ///
+    /// Bucket sentinels stay in the list and in `buckets`, so nothing has to be freed. With the
+    /// smaller `size` they sit in the middle of the chains of the remaining buckets, and they are
+    /// reused as is when `size` grows again. Entries inserted concurrently may or may not survive.
+    pub fn clear(&self, guard: &Guard) {
+        for key in self.keys(guard) {
+            let _ = self.delete(&key, guard);
+        }
+        self.size.store(2, Ordering::Release);
+    }
+
+    /// Inserts every pair of `items` within the epoch pinned by `guard`. Returns the number of
+    /// pairs inserted. Pairs whose key is already present are dropped.
+    ///
+    /// Each pair goes through `insert`, so `size` keeps doubling as the batch fills the map.
+    pub fn insert_many<I: IntoIterator<Item = (usize, V)>>(
+        &self,
+        items: I,
+        guard: &Guard,
+    ) -> usize {
+        let mut inserted = 0;
+        for (key, value) in items {
+            if self.insert(&key, value, guard).is_ok() {
+                inserted += 1;
+            }
+        }
+        inserted
+    }
+
+    /// Inserts every pair of `items`. If the key is already present, its value is replaced by
+    /// `merge(key, existing, incoming)`.
+    ///
+    /// The merged value is swapped in with a CAS from the value it was built from, so the key
+    /// never appears absent to readers. If another thread changed or deleted the entry in the
+    /// meantime, the merged value is dropped and `merge` runs again with the current value, or
+    /// `incoming` is inserted as is. `merge` may thus run several times for one incoming pair under
+    /// contention, and no value is lost.
+    pub fn merge_from_iter<I, F>(&self, items: I, mut merge: F, guard: &Guard)
+    where
+        I: IntoIterator<Item = (usize, V)>,
+        F: FnMut(&usize, &V, &V) -> V,
+    {
+        for (key, mut incoming) in items {
+            loop {
+                match self.insert(&key, incoming, guard) {
+                    Ok(()) => break,
+                    Err(err) => incoming = err.into_value().unwrap(),
+                }
+                let (_, found, cursor) = self.find(&key, guard);
+                if !found {
+                    continue;
+                }
+                let item = cursor.lookup().unwrap();
+                let current = item.value.load(Ordering::Acquire, guard);
+                let existing = match unsafe { current.as_ref() } {
+                    Some(existing) => existing,
+                    None => continue,
+                };
+                let merged = merge(&key, existing, &incoming);
+                let weight = self.weigh(&merged);
+                if item
+                    .value
+                    .compare_and_set(current, Owned::new(merged), Ordering::AcqRel, guard)
+                    .is_ok()
+                {
+                    self.weight.fetch_add(weight, Ordering::AcqRel);
+                    self.weight
+                        .fetch_sub(self.weigh(existing), Ordering::AcqRel);
+                    unsafe { guard.defer_destroy(current) };
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Returns the number of entries, as an estimate. Same as `estimate_len`.
+    pub fn len(&self) -> usize {
+        self.estimate_len()
+    }
+
+    /// Returns the number of entries in O(1), by summing the stripes of the entry counter.
+    ///
+    /// The counter is updated right after each insert and delete, so under concurrent mutation
+    /// it may briefly disagree with what `lookup` reports. Once all operations have completed it
+    /// equals `exact_len`.
+    pub fn estimate_len(&self) -> usize {
+        self.count.sum()
+    }
+
+    /// Returns the number of entries by walking the list and counting its data nodes, in O(n).
+    ///
+    /// Unlike `estimate_len`, this doesn't depend on the counter catching up with the list. It is
+    /// exact while the map is not modified. Under concurrent mutation it is no more precise, as
+    /// entries on either side of the walk may change.
+    pub fn exact_len(&self, guard: &Guard) -> usize {
+        self.iter(guard).count()
+    }
+
+    /// Returns whether the map has no entries. Same caveat as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the largest number of data nodes between two consecutive sentinels, i.e. the length
+    /// of the longest bucket chain. Walks the whole list.
+    ///
+    /// With well-spread keys this stays around the load factor, so a much larger value points at
+    /// keys clustering in one bucket. Only buckets that have been initialized have a sentinel, so
+    /// a chain may span several buckets that haven't been accessed since `size` last grew.
+    pub fn max_chain_len(&self, guard: &Guard) -> usize {
+        let mut max = 0;
+        let mut len = 0;
+        let mut cursor = self.list.head(guard);
+        while let Some(item) = cursor.lookup() {
+            if item.key & 1 == 0 {
+                len = 0;
+            } else if item.value(guard).is_some() {
+                len += 1;
+                max = cmp::max(max, len);
+            }
+            if !self.advance(&mut cursor, item.key, guard) {
+                break;
+            }
+        }
+        max
+    }
+
+    /// Returns the average number of entries per bucket, `len() / size`. Without a weigher, `size`
+    /// doubles once this exceeds the configured load factor.
+    ///
+    /// `size` is read again after the entries are counted, and both are re-read if it changed in
+    /// between, so the ratio never mixes the count with a `size` from before a resize.
+    pub fn load_factor(&self) -> f64 {
+        let mut size = self.size.load(Ordering::Acquire);
+        loop {
+            let count = self.len();
+            let current = self.size.load(Ordering::Acquire);
+            if current == size {
+                return count as f64 / size as f64;
+            }
+            size = current;
+        }
+    }
+
+    /// Registers `f` to be called with `(bucket_index, chain_len)` when an insert finds more than
+    /// `threshold` entries in its bucket ahead of its insertion point. Replaces any previously
+    /// registered hook.
+    ///
+    /// The hook fires opportunistically: only inserts check, they only count the part of the chain
+    /// before the new entry, and the count is a snapshot that concurrent operations may already
+    /// have changed. A long chain that no insert walks through is never reported. While a hook is
+    /// registered, every insert walks its bucket once more to count the entries.
+    pub fn on_long_chain(&mut self, threshold: usize, f: Box<dyn Fn(usize, usize) + Send + Sync>) {
+        self.long_chain_hook = Some(LongChainHook { threshold, f });
+    }
+
+    /// Iterates over the entries, in split order, within the epoch pinned by `guard`.
+    ///
+    /// Every key that is present for the whole iteration and not deleted during it is yielded
+    /// exactly once. A key inserted or deleted while iterating may or may not be yielded, but never
+    /// more than once: each step moves to the first node whose split-order key is larger than the
+    /// last one, even if the walk has to restart from the head after a failed unlink.
+    pub fn stable_iter<'g>(&'g self, guard: &'g Guard) -> StableIter<'g, V> {
+        StableIter {
+            map: self,
+            cursor: Some(self.list.head(guard)),
+            guard,
+        }
+    }
+
+    /// Iterates over the entries, in split order rather than key order, skipping sentinel nodes.
+    /// The same as `stable_iter`, which documents the guarantees under concurrent modification.
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> Iter<'g, V> {
+        self.stable_iter(guard)
+    }
+
+    /// Returns the first entry in split order, i.e. the entry whose key has the smallest bit
+    /// reversal, which is usually not the smallest key. Sentinel nodes are skipped.
+    pub fn first<'g>(&'g self, guard: &'g Guard) -> Option<(usize, &'g V)> {
+        self.iter(guard).next()
+    }
+
+    /// Deletes the entries one at a time, in split order, and yields their keys with their values
+    /// moved out, like `remove`. Entries not yet reached when the iterator is dropped stay in the
+    /// map.
+    pub fn drain(&mut self) -> Drain<'_, V> {
+        Drain { map: self }
+    }
+
+    /// Iterates over the keys, in split order. See `iter`.
+    pub fn keys<'g>(&'g self, guard: &'g Guard) -> impl Iterator<Item = usize> + 'g {
+        self.iter(guard).map(|(key, _)| key)
+    }
+
+    /// Iterates over the values, in split order of their keys. See `iter`.
+    pub fn values<'g>(&'g self, guard: &'g Guard) -> impl Iterator<Item = &'g V> + 'g {
+        self.iter(guard).map(|(_, value)| value)
+    }
+
+    /// Iterates over the entries whose keys lie in `lo..hi`, in split order. See `iter`.
+    ///
+    /// Nodes are sorted by split order rather than by key, so this walks the whole list: it takes
+    /// O(n) time however narrow the range is.
+    pub fn scan<'g>(
+        &'g self,
+        lo: usize,
+        hi: usize,
+        guard: &'g Guard,
+    ) -> impl Iterator<Item = (usize, &'g V)> + 'g {
+        self.iter(guard)
+            .filter(move |(key, _)| (lo..hi).contains(key))
+    }
+
+    /// Stops `size` from doubling past `cap`, rounded down to a power of two (and at least 2). A
+    /// `size` that is already larger is left as is.
+    ///
+    /// Once the cap is hit, further inserts lengthen the bucket chains instead of adding buckets,
+    /// so lookups get slower as the map fills up.
+    pub fn max_size_cap(&self, cap: usize) {
+        let cap = cmp::max(cap, 2);
+        let cap = 1 << (mem::size_of::<usize>() * 8 - 1 - cap.leading_zeros() as usize);
+        self.max_size.store(cap, Ordering::Relaxed);
+    }
+
+    /// Returns `(size, highest_initialized_bucket)`, where the latter is 0 if no bucket has been
+    /// initialized yet.
+    ///
+    /// Resizing a split-ordered list never moves entries: doubling `size` only makes more buckets
+    /// addressable, and each new bucket gets its sentinel lazily on its first access. The gap
+    /// between the two numbers is roughly the initialization work still ahead. Both are read
+    /// independently, so under concurrent growth the pair is only weakly consistent.
+    pub fn resize_frontier(&self, guard: &Guard) -> (usize, usize) {
+        let size = self.size.load(Ordering::Acquire);
+        let mut highest = 0;
+        self.buckets.for_each(|index, _| highest = index, guard);
+        (size, highest)
+    }
+
+    /// Returns whether every entry of `self` is in `other` with an equal value.
+    ///
+    /// Like `to_sorted_vec_by`, the answer is only meaningful when both maps are quiescent.
+    pub fn is_subset(&self, other: &SplitOrderedList<V>, guard: &Guard) -> bool
+    where
+        V: PartialEq,
+    {
+        self.stable_iter(guard)
+            .all(|(key, value)| other.lookup(&key, guard) == Some(value))
+    }
+
+    /// Returns whether every entry of `other` is in `self` with an equal value. See `is_subset`.
+    pub fn is_superset(&self, other: &SplitOrderedList<V>, guard: &Guard) -> bool
+    where
+        V: PartialEq,
+    {
+        other.is_subset(self, guard)
+    }
+
+    /// Returns a snapshot of all entries sorted by `cmp`, e.g. by value for a top-N report.
+    ///
+    /// The snapshot is only meaningful when the map is quiescent. Entries inserted or deleted
+    /// concurrently may or may not be included.
+    pub fn to_sorted_vec_by<F>(&self, mut cmp: F, guard: &Guard) -> Vec<(usize, V)>
+    where
+        V: Clone,
+        F: FnMut(&(usize, V), &(usize, V)) -> cmp::Ordering,
+    {
+        let mut entries = Vec::new();
+        self.for_each(|key, value| entries.push((key, value.clone())), guard);
+        entries.sort_by(|a, b| cmp(a, b));
+        entries
+    }
+
+    /// Consumes the map and returns an immutable copy optimized for lookups.
+    pub fn freeze(self) -> FrozenMap<V> {
+        let mut entries = self.into_entries();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        FrozenMap::from_sorted(entries)
+    }
+
+    /// Consumes the map and moves the values out of its data nodes, in split order.
+    ///
+    /// Owning the map means no other thread can reach the nodes, so the values can be taken
+    /// without a guard. The emptied nodes are then freed with the list.
+    fn into_entries(self) -> Vec<(usize, V)> {
+        let mut entries = Vec::with_capacity(self.count.sum());
+        unsafe {
+            let guard = unprotected();
+            let mut cursor = self.list.head(guard);
+            while let Some(item) = cursor.lookup() {
+                let value = item.value.swap(Shared::null(), Ordering::Relaxed, guard);
+                if !value.is_null() {
+                    entries.push((Self::user_key(item.key), *value.into_owned().into_box()));
+                }
+                if !self.advance(&mut cursor, item.key, guard) {
+                    break;
+                }
+            }
+        }
+        entries
+    }
+
+    /// Encodes all entries into a compact binary checkpoint, readable by `restore`.
+    ///
+    /// Like any traversal, the checkpoint is only a consistent snapshot if the map is quiescent.
+    pub fn checkpoint(&self, guard: &Guard) -> Vec<u8>
+    where
+        V: Encode,
+    {
+        let mut count = 0;
+        let mut entries = Vec::new();
+        let mut value_buf = Vec::new();
+        self.for_each(
+            |key, value| {
+                value_buf.clear();
+                value.encode(&mut value_buf);
+                checkpoint::write_u64(&mut entries, key as u64);
+                checkpoint::write_u64(&mut entries, value_buf.len() as u64);
+                entries.extend_from_slice(&value_buf);
+                count += 1;
+            },
+            guard,
+        );
+
+        let mut buf = Vec::with_capacity(mem::size_of::<u64>() + entries.len());
+        checkpoint::write_u64(&mut buf, count);
+        buf.extend_from_slice(&entries);
+        buf
+    }
+
+    /// Rebuilds a map from a buffer produced by `checkpoint`.
+    pub fn restore(mut bytes: &[u8]) -> Result<Self, RestoreError>
+    where
+        V: Encode,
+    {
+        let count = checkpoint::read_u64(&mut bytes)?;
+        // Every entry takes at least 16 bytes for its key and length, so a corrupt count can't
+        // make this allocate more than the input justifies
+        let capacity = usize::try_from(count).unwrap_or(usize::MAX);
+        let map = Self::with_capacity(capacity.min(bytes.len() / 16));
+        let guard = crossbeam_epoch::pin();
+
+        for _ in 0..count {
+            let key = checkpoint::read_u64(&mut bytes)?;
+            let key = match usize::try_from(key) {
+                Ok(key) if key & Self::HI_MASK == 0 => key,
+                _ => return Err(RestoreError::InvalidKey(key)),
+            };
+            let len = checkpoint::read_u64(&mut bytes)?;
+            let len = usize::try_from(len).map_err(|_| RestoreError::Truncated)?;
+            let value = checkpoint::read_bytes(&mut bytes, len)?;
+            let value = V::decode(value).ok_or(RestoreError::InvalidValue(key))?;
+            if map.insert(&key, value, &guard).is_err() {
+                return Err(RestoreError::DuplicateKey(key));
+            }
+        }
+
+        if !bytes.is_empty() {
+            return Err(RestoreError::TrailingBytes);
+        }
+        Ok(map)
+    }
+}
+
+impl<T> SplitOrderedList<Arc<T>> {
+    /// Returns a new reference to the `Arc` stored for `key`. Cloning only bumps the reference
+    /// count, and the result stays usable after `guard` is dropped.
+    pub fn get_arc(&self, key: &usize, guard: &Guard) -> Option<Arc<T>> {
+        self.lookup(key, guard).cloned()
+    }
+}
+
+#[cfg(feature = "dashmap")]
+impl<V> SplitOrderedList<V> {
+    /// Copies all entries of `map`, with `size` already large enough that the copy doesn't resize.
+    ///
+    /// Entries inserted into or removed from `map` concurrently may or may not be copied.
+    pub fn from_dashmap(map: &dashmap::DashMap<usize, V>) -> Self
+    where
+        V: Clone,
+    {
+        let list = Self::with_capacity(map.len());
+        let guard = crossbeam_epoch::pin();
+        for entry in map.iter() {
+            // Keys are unique in `map`
+            let _ = list.insert(entry.key(), entry.value().clone(), &guard);
+        }
+        list
+    }
+
+    /// Consumes the map and moves its entries into a `DashMap` of matching capacity.
+    pub fn into_dashmap(self) -> dashmap::DashMap<usize, V> {
+        let entries = self.into_entries();
+        let map = dashmap::DashMap::with_capacity(entries.len());
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<V> SplitOrderedList<V> {
+    /// Creates a map whose load factor follows the read/write mix seen by the metrics counters,
+    /// staying within `min..=max`. It starts at `min`.
+    ///
+    /// The operations are counted in windows of `ADAPT_WINDOW`. At the end of each, the load factor
+    /// is doubled if the window's writes (inserts and deletes) outnumber its lookups more than two
+    /// to one, and halved if lookups outnumber writes more than two to one. In between, it is left
+    /// as is, so a balanced workload doesn't make it flap. The factor thus follows the recent mix
+    /// rather than the whole history, and moves at most one step per window. Only the resize
+    /// threshold changes: buckets are never removed, and the factor never drops below `min`.
+    pub fn with_adaptive_load_factor(min: usize, max: usize) -> Self {
+        assert!(1 <= min && min <= max);
+        Self {
+            load_factor: AtomicUsize::new(min),
+            adaptive_load_factor: Some((min, max)),
+            ..Self::default()
+        }
+    }
+
+    /// Number of lookups, inserts and deletes over which `adapt_load_factor` weighs the mix.
+    const ADAPT_WINDOW: u64 = 1024;
+
+    /// Called on every insert. Adjusts the load factor once the current window is full, and
+    /// starts the next one.
+    fn adapt_load_factor(&self) {
+        let (min, max) = match self.adaptive_load_factor {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        let writes = self.inserts.load(Ordering::Relaxed) + self.deletes.load(Ordering::Relaxed);
+        let window_writes = self.window_writes.load(Ordering::Relaxed);
+        let reads = lookups.saturating_sub(self.window_lookups.load(Ordering::Relaxed));
+        let writes_in_window = writes.saturating_sub(window_writes);
+        if reads + writes_in_window < Self::ADAPT_WINDOW {
+            return;
+        }
+        // Only one of the threads that see the window full gets to close it
+        if self
+            .window_writes
+            .compare_exchange(window_writes, writes, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        self.window_lookups.store(lookups, Ordering::Relaxed);
+
+        let writes = writes_in_window;
+        let load_factor = self.load_factor.load(Ordering::Relaxed);
+        let adapted = if writes > reads * 2 {
+            cmp::min(load_factor * 2, max)
+        } else if reads > writes * 2 {
+            cmp::max(load_factor / 2, min)
+        } else {
+            load_factor
+        };
+        if adapted != load_factor {
+            self.load_factor.store(adapted, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of lookups since construction. Never decreases.
+    pub fn total_lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    /// Number of successful inserts since construction. Never decreases.
+    pub fn total_inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+
+    /// Number of successful deletes since construction. Never decreases.
+    ///
+    /// Once all operations have completed, `total_inserts() - total_deletes()` is the number of
+    /// entries in the map. Under concurrent mutation the two counters are read independently, so
+    /// the difference is only approximate.
+    pub fn total_deletes(&self) -> u64 {
+        self.deletes.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "debug")]
+impl<V> SplitOrderedList<V> {
+    /// Compares the pointer stored for the bucket with the bucket's sentinel in the list. A
+    /// mismatch means the bucket array holds a stale pointer.
+    pub fn debug_bucket(&self, bucket_index: usize, guard: &Guard) -> BucketDebug {
+        let stored = self
+            .buckets
+            .get(bucket_index, guard)
+            .load(Ordering::Acquire, guard);
+
+        let key = bucket_index.reverse_bits();
+        let sentinel = loop {
+            let mut cursor = self.list.head(guard);
+            match Cursor::find_harris_michael(&mut cursor, &key, guard) {
+                Ok(true) => break cursor.curr(),
+                Ok(false) => break crossbeam_epoch::Shared::null(),
+                Err(()) => continue,
+            }
+        };
+
+        BucketDebug {
+            stored_ptr: stored.as_raw() as *const (),
+            list_sentinel_ptr: sentinel.as_raw() as *const (),
+            matches: stored == sentinel,
+        }
+    }
+}
+
+#[cfg(test)]
+impl<V> SplitOrderedList<V> {
+    /// Checks the structural invariants of the list, and describes the first one found violated.
+    ///
+    /// Walking the list, split-order keys must be strictly increasing, and a node must be a
+    /// sentinel exactly when the low bit of its key is clear. A data node must hold a value, since
+    /// a sealed one is unlinked by its delete. Every initialized bucket must point at the sentinel
+    /// with its reversed index in the list, and `len` must equal the number of data nodes. The
+    /// checks are only meaningful while no other thread modifies the map.
+    pub fn validate(&self, guard: &Guard) -> Result<(), String> {
+        let mut sentinels = Vec::new();
+        let mut data = 0;
+        let mut last = None;
+        let mut cursor = self.list.head(guard);
+        while let Some(item) = cursor.lookup() {
+            if let Some(last) = last {
+                if item.key <= last {
+                    return Err(format!("key {:#x} follows key {:#x}", item.key, last));
+                }
+            }
+            last = Some(item.key);
+            match (item.key & 1 == 0, item.value(guard).is_none()) {
+                (true, true) => sentinels.push((item.key, cursor.curr().as_raw())),
+                (false, false) => data += 1,
+                (true, false) => return Err(format!("data node with even key {:#x}", item.key)),
+                (false, true) => {
+                    return Err(format!("sealed data node {:#x} still linked", item.key))
+                }
+            }
+            if !self.advance(&mut cursor, item.key, guard) {
+                break;
+            }
+        }
+
+        let mut result = Ok(());
+        self.buckets.for_each(
+            |index, stored| {
+                if result.is_err() {
+                    return;
+                }
+                let key = index.reverse_bits();
+                match sentinels.binary_search_by_key(&key, |&(key, _)| key) {
+                    Ok(i) if sentinels[i].1 == stored.as_raw() => {}
+                    Ok(_) => {
+                        result = Err(format!("bucket {} does not point at its sentinel", index))
+                    }
+                    Err(_) => result = Err(format!("sentinel of bucket {} is missing", index)),
+                }
+            },
+            guard,
+        );
+        result?;
+
+        let len = self.len();
+        if len != data {
+            return Err(format!(
+                "len is {} but the list holds {} entries",
+                len, data
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Serializes the entries as a map from user keys to values, leaving out sentinel nodes.
+#[cfg(feature = "serde")]
+impl<V: serde::Serialize> serde::Serialize for SplitOrderedList<V> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let guard = crossbeam_epoch::pin();
+        serializer.collect_map(self.iter(&guard))
+    }
+}
+
+/// Deserializes a map from user keys to values into a fresh list. Later entries with the same key
+/// overwrite earlier ones.
+#[cfg(feature = "serde")]
+impl<'de, V: serde::Deserialize<'de>> serde::Deserialize<'de> for SplitOrderedList<V> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use core::marker::PhantomData;
+        use serde::de::{self, MapAccess, Visitor};
+
+        struct MapVisitor<V>(PhantomData<V>);
+
+        impl<'de, V: serde::Deserialize<'de>> Visitor<'de> for MapVisitor<V> {
+            type Value = SplitOrderedList<V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a map with usize keys below the high bit")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+                let mut map = SplitOrderedList::new();
+                while let Some((key, value)) = access.next_entry::<usize, V>()? {
+                    if key & SplitOrderedList::<V>::HI_MASK != 0 {
+                        return Err(de::Error::custom(format_args!("invalid key {}", key)));
+                    }
+                    map.extend(Some((key, value)));
+                }
+                Ok(map)
+            }
+        }
+
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_epoch as epoch;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 10_000;
+
+    #[test]
+    fn update_replaces_in_place() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(
+            map.update(&3, |v| v + 1, &guard),
+            Err(MapError::KeyNotFound)
+        );
+        map.insert(&3, 10, &guard).unwrap();
+        assert_eq!(map.update(&3, |v| v + 1, &guard), Ok(()));
+        assert_eq!(map.lookup(&3, &guard), Some(&11));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_updates_sum_up_exactly() {
+        let map = SplitOrderedList::new();
+        map.insert(&7, 0, &epoch::pin()).unwrap();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        map.update(&7, |v| v + 1, &epoch::pin()).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(map.lookup(&7, &epoch::pin()), Some(&(THREADS * ROUNDS)));
+    }
+
+    /// Increments race with deletes and re-inserts of the key. Every increment that reported
+    /// success must end up either in a deleted value or in the final one.
+    #[test]
+    fn updates_never_land_on_deleted_nodes() {
+        let map = SplitOrderedList::new();
+        map.insert(&7, 0, &epoch::pin()).unwrap();
+        let updated = AtomicUsize::new(0);
+        let deleted = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        if map.update(&7, |v| v + 1, &epoch::pin()).is_ok() {
+                            updated.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    let guard = epoch::pin();
+                    if let Ok(value) = map.delete(&7, &guard) {
+                        deleted.fetch_add(*value, Ordering::Relaxed);
+                    }
+                    let _ = map.insert(&7, 0, &guard);
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    if let Some(value) = map.insert_or_replace(&7, 0, &epoch::pin()) {
+                        deleted.fetch_add(value, Ordering::Relaxed);
+                    }
+                }
+            });
+        });
+        let guard = epoch::pin();
+        let last = map.lookup(&7, &guard).copied().unwrap_or(0);
+        assert_eq!(
+            deleted.load(Ordering::Relaxed) + last,
+            updated.load(Ordering::Relaxed)
+        );
+        map.validate(&guard).unwrap();
+    }
+
+    #[test]
+    fn compute_covers_all_transitions() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        // Absent and left absent
+        map.compute(&1, |_| None, &guard);
+        assert_eq!(map.lookup(&1, &guard), None);
+        // Inserted
+        map.compute(&1, |v| Some(v.map_or(1, |v| v + 1)), &guard);
+        assert_eq!(map.lookup(&1, &guard), Some(&1));
+        // Replaced
+        map.compute(&1, |v| Some(v.map_or(1, |v| v + 1)), &guard);
+        assert_eq!(map.lookup(&1, &guard), Some(&2));
+        // Deleted
+        map.compute(&1, |_| None, &guard);
+        assert_eq!(map.lookup(&1, &guard), None);
+        assert_eq!(map.len(), 0);
+    }
+
+    /// Every compute either increments the value or deletes it, so the deleted values and the
+    /// final one must add up to the number of increments.
+    #[test]
+    fn concurrent_computes_never_lose_updates() {
+        let map = SplitOrderedList::new();
+        let deleted = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        let increment = |v: Option<&usize>| Some(v.map_or(1, |v| v + 1));
+                        map.compute(&7, increment, &epoch::pin());
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    // Only the last call of `f` decides
+                    let mut seen = None;
+                    map.compute(
+                        &7,
+                        |v| {
+                            seen = v.copied();
+                            None
+                        },
+                        &epoch::pin(),
+                    );
+                    deleted.fetch_add(seen.unwrap_or(0), Ordering::Relaxed);
+                }
+            });
+        });
+        let guard = epoch::pin();
+        let last = map.lookup(&7, &guard).copied().unwrap_or(0);
+        assert_eq!(deleted.load(Ordering::Relaxed) + last, THREADS * ROUNDS);
+    }
+
+    /// The parent formula of the paper: the index with its most significant set bit cleared.
+    fn paper_parent(bucket_index: usize) -> usize {
+        if bucket_index == 0 {
+            return 0;
+        }
+        let msb = mem::size_of::<usize>() * 8 - 1 - bucket_index.leading_zeros() as usize;
+        bucket_index - (1 << msb)
+    }
+
+    #[test]
+    fn get_parent_matches_the_paper() {
+        let parents: Vec<_> = (0..16).map(SplitOrderedList::<()>::get_parent).collect();
+        assert_eq!(parents, [0, 0, 0, 1, 0, 1, 2, 3, 0, 1, 2, 3, 4, 5, 6, 7]);
+
+        for bucket_index in (0..4096).chain([usize::MAX >> 1, 1 << 40, (1 << 40) + 3]) {
+            let parent = SplitOrderedList::<()>::get_parent(bucket_index);
+            assert_eq!(
+                parent,
+                paper_parent(bucket_index),
+                "bucket {}",
+                bucket_index
+            );
+            if bucket_index != 0 {
+                assert!(parent < bucket_index, "bucket {}", bucket_index);
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_are_initialized_through_their_ancestors() {
+        let map = SplitOrderedList::<()>::new();
+        let guard = epoch::pin();
+        map.lookup_bucket(13, &guard);
+        let mut initialized = Vec::new();
+        map.buckets
+            .for_each(|index, _| initialized.push(index), &guard);
+        assert_eq!(initialized, [0, 1, 5, 13]);
+    }
+
+    /// Threads insert keys into fresh maps, racing on the initialization of the buckets every
+    /// resize opens up. A thread that finds a sentinel already linked must not go on with its
+    /// bucket still null, so each key must be found right after its insert.
+    #[test]
+    fn racing_bucket_initializations_lose_no_insert() {
+        for _ in 0..10 {
+            let map = SplitOrderedList::new();
+            thread::scope(|s| {
+                for t in 0..THREADS {
+                    let map = &map;
+                    s.spawn(move || {
+                        for i in 0..ROUNDS {
+                            let guard = epoch::pin();
+                            let key = t * ROUNDS + i;
+                            map.insert(&key, key, &guard).unwrap();
+                            assert_eq!(map.lookup(&key, &guard), Some(&key));
+                        }
+                    });
+                }
+            });
+            assert_eq!(map.exact_len(&epoch::pin()), THREADS * ROUNDS);
+        }
+    }
+
+    /// With the default load factor of 2 and an initial `size` of 2, `size` doubles on the insert
+    /// that takes `count` past `size * 2`, i.e. on the 5th and then on the 9th insert.
+    #[test]
+    fn resizes_right_after_the_threshold() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        let mut sizes = Vec::new();
+        for key in 0..17 {
+            map.insert(&key, key, &guard).unwrap();
+            sizes.push(map.size.load(Ordering::Relaxed));
+        }
+        assert_eq!(sizes, [2, 2, 2, 2, 4, 4, 4, 4, 8, 8, 8, 8, 8, 8, 8, 8, 16]);
+
+        // `try_insert` leaves the doubling to `maybe_resize`
+        let map = SplitOrderedList::new();
+        for key in 0..9 {
+            map.try_insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.size.load(Ordering::Relaxed), 2);
+        map.maybe_resize();
+        assert_eq!(map.size.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn shrink_keeps_remaining_keys() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..1000 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        let grown = map.size.load(Ordering::Relaxed);
+        for key in (0..1000).filter(|key| key % 100 != 0) {
+            map.delete(&key, &guard).unwrap();
+        }
+        map.shrink(&guard);
+        let shrunk = map.size.load(Ordering::Relaxed);
+        assert!(shrunk < grown);
+        assert!(!map.underloaded(shrunk));
+        for key in 0..1000 {
+            assert_eq!(map.lookup(&key, &guard).is_some(), key % 100 == 0);
+        }
+    }
+
+    /// Bucket indices of the sentinels linked in the list, in split order.
+    fn sentinels<V>(map: &SplitOrderedList<V>, guard: &Guard) -> Vec<usize> {
+        let mut sentinels = Vec::new();
+        let mut cursor = map.list.head(guard);
+        while let Some(item) = cursor.lookup() {
+            if item.key & 1 == 0 {
+                sentinels.push(item.key.reverse_bits());
+            }
+            if !map.advance(&mut cursor, item.key, guard) {
+                break;
+            }
+        }
+        sentinels
+    }
+
+    #[test]
+    fn shrink_retires_the_upper_sentinels() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..1000 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        let grown = map.size.load(Ordering::Relaxed);
+        assert!(sentinels(&map, &guard)
+            .iter()
+            .any(|&bucket| bucket >= grown / 2));
+
+        for key in 10..1000 {
+            map.delete(&key, &guard).unwrap();
+        }
+        map.shrink(&guard);
+        let shrunk = map.size.load(Ordering::Relaxed);
+        assert!(shrunk < grown);
+        let remaining = sentinels(&map, &guard);
+        assert!(remaining.iter().all(|&bucket| bucket < shrunk));
+        assert!(remaining.contains(&0));
+        // The retired buckets are nulled as well
+        let mut initialized = Vec::new();
+        map.buckets
+            .for_each(|index, _| initialized.push(index), &guard);
+        assert!(initialized.iter().all(|&bucket| bucket < shrunk));
+        for key in 0..1000 {
+            assert_eq!(map.lookup(&key, &guard).is_some(), key < 10);
+        }
+
+        // Growing again brings the sentinels back
+        for key in 10..1000 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.size.load(Ordering::Relaxed), grown);
+        assert!(sentinels(&map, &guard)
+            .iter()
+            .any(|&bucket| bucket >= shrunk));
+        assert_eq!(map.exact_len(&guard), 1000);
+    }
+
+    /// Lookups of keys that are never deleted run while most other keys are deleted and `size`
+    /// shrinks and grows again underneath them. No lookup may miss, whatever `size` it started
+    /// from.
+    #[test]
+    fn lookups_never_miss_across_shrinks() {
+        let map = SplitOrderedList::new();
+        let kept = |key: usize| key.is_multiple_of(64);
+        for key in 0..4096 {
+            map.insert(&key, key, &epoch::pin()).unwrap();
+        }
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (map, done) = (&map, &done);
+                s.spawn(move || {
+                    while done.load(Ordering::Acquire) == 0 {
+                        let guard = epoch::pin();
+                        for key in (t * 64..4096).step_by(64 * THREADS) {
+                            assert_eq!(map.lookup(&key, &guard), Some(&key));
+                        }
+                    }
+                });
+            }
+            for _ in 0..20 {
+                for key in (0..4096).filter(|&key| !kept(key)) {
+                    let guard = epoch::pin();
+                    map.delete(&key, &guard).unwrap();
+                    if key % 256 == 1 {
+                        map.shrink(&guard);
+                    }
+                }
+                map.shrink(&epoch::pin());
+                for key in (0..4096).filter(|&key| !kept(key)) {
+                    map.insert(&key, key, &epoch::pin()).unwrap();
+                }
+            }
+            done.store(1, Ordering::Release);
+        });
+        let guard = epoch::pin();
+        assert_eq!(map.exact_len(&guard), 4096);
+        map.validate(&guard).unwrap();
+    }
+
+    /// Threads insert keys and delete most of them again while another thread keeps shrinking,
+    /// retiring sentinels the writers may be starting from. No insert or delete may get lost.
+    #[test]
+    fn shrinks_racing_writers_lose_nothing() {
+        let map = SplitOrderedList::new();
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            let writers: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let map = &map;
+                    s.spawn(move || {
+                        for i in 0..ROUNDS {
+                            let guard = epoch::pin();
+                            let key = t * ROUNDS + i;
+                            map.insert(&key, key, &guard).unwrap();
+                            if i % 8 != 0 {
+                                assert_eq!(map.delete(&key, &guard), Ok(&key));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            s.spawn(|| {
+                while done.load(Ordering::Acquire) == 0 {
+                    map.shrink(&epoch::pin());
+                }
+            });
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(1, Ordering::Release);
+        });
+        let guard = epoch::pin();
+        for key in 0..THREADS * ROUNDS {
+            assert_eq!(
+                map.lookup(&key, &guard).is_some(),
+                (key % ROUNDS).is_multiple_of(8)
+            );
+        }
+        map.validate(&guard).unwrap();
+    }
+
+    #[test]
+    fn insert_or_replace_returns_the_old_value() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(map.insert_or_replace(&4, "a".to_string(), &guard), None);
+        assert_eq!(
+            map.insert_or_replace(&4, "b".to_string(), &guard),
+            Some("a".to_string())
+        );
+        assert_eq!(map.lookup(&4, &guard).map(String::as_str), Some("b"));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn retain_leaves_entries_replaced_meanwhile() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..10 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        map.retain(
+            |&key, &value| {
+                if key == 3 {
+                    // Replaced after `f` saw it, so the delete must not go through
+                    map.update(&3, |v| v + 100, &guard).unwrap();
+                }
+                value % 2 == 0
+            },
+            &guard,
+        );
+        let mut left: Vec<_> = map.iter(&guard).map(|(key, &value)| (key, value)).collect();
+        left.sort_unstable();
+        assert_eq!(left, [(0, 0), (2, 2), (3, 103), (4, 4), (6, 6), (8, 8)]);
+    }
+
+    /// Neither `Clone` nor `Copy`, so values have to be moved out of the map
+    #[derive(Debug, PartialEq)]
+    struct Token(usize);
+
+    #[test]
+    fn freeze_moves_values_out() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..100 {
+            map.insert(&key, Token(key * 2), &guard).unwrap();
+        }
+        map.delete(&5, &guard).unwrap();
+        drop(guard);
+        let frozen = map.freeze();
+        assert_eq!(frozen.len(), 99);
+        assert_eq!(frozen.get(&7), Some(&Token(14)));
+        assert_eq!(frozen.get(&5), None);
+    }
+
+    #[cfg(feature = "dashmap")]
+    #[test]
+    fn into_dashmap_moves_values_out() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..100 {
+            map.insert(&key, Token(key), &guard).unwrap();
+        }
+        drop(guard);
+        let dashmap = map.into_dashmap();
+        assert_eq!(dashmap.len(), 100);
+        assert_eq!(*dashmap.get(&42).unwrap(), Token(42));
+    }
+
+    /// Merged values are swapped in place, so keys present from the start never read as absent
+    /// while concurrent merges run, and no incoming value is lost.
+    #[test]
+    fn merges_are_swapped_in_place() {
+        let map = SplitOrderedList::new();
+        for key in 0..10 {
+            map.insert(&key, 0, &epoch::pin()).unwrap();
+        }
+        let merged = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    let items = (0..ROUNDS).map(|i| (i % 10, 1));
+                    let merge = |_: &usize, existing: &usize, incoming: &usize| existing + incoming;
+                    map.merge_from_iter(items, merge, &epoch::pin());
+                    merged.fetch_add(1, Ordering::Release);
+                });
+            }
+            s.spawn(|| {
+                while merged.load(Ordering::Acquire) < THREADS {
+                    let guard = epoch::pin();
+                    for key in 0..10 {
+                        assert!(map.lookup(&key, &guard).is_some());
+                    }
+                }
+            });
+        });
+        let guard = epoch::pin();
+        let total: usize = (0..10).map(|key| map.lookup(&key, &guard).unwrap()).sum();
+        assert_eq!(total, THREADS * ROUNDS);
+
+        let mut map: SplitOrderedList<_> = vec![(1, "a"), (2, "b"), (1, "c")].into_iter().collect();
+        map.extend(Some((2, "d")));
+        assert_eq!(map.lookup(&1, &guard), Some(&"c"));
+        assert_eq!(map.lookup(&2, &guard), Some(&"d"));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn restore_round_trips_and_rejects_bogus_counts() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..50 {
+            map.insert(&key, key.to_string(), &guard).unwrap();
+        }
+        let restored = SplitOrderedList::<String>::restore(&map.checkpoint(&guard)).unwrap();
+        assert!(restored.is_subset(&map, &guard) && map.is_subset(&restored, &guard));
+
+        // Claims more entries than could ever fit in the input
+        let bogus = u64::MAX.to_le_bytes();
+        assert_eq!(
+            SplitOrderedList::<String>::restore(&bogus).err(),
+            Some(RestoreError::Truncated)
+        );
+    }
+
+    #[test]
+    fn insert_if_bucket_under_rejects_full_buckets() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(map.insert_if_bucket_under(&0, 1, 1, &guard), Ok(()));
+        assert_eq!(
+            map.insert_if_bucket_under(&0, 2, 5, &guard),
+            Err(InsertRejected::Occupied(2))
+        );
+        // Bucket 0 of 2 already holds key 0
+        assert_eq!(
+            map.insert_if_bucket_under(&2, 3, 1, &guard),
+            Err(InsertRejected::BucketFull(3))
+        );
+        assert_eq!(map.insert_if_bucket_under(&1, 4, 1, &guard), Ok(()));
+        assert_eq!(map.insert(&1, 5, &guard), Err(MapError::KeyExists(5)));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn load_factor_adapts_once_per_window() {
+        let map = SplitOrderedList::with_adaptive_load_factor(1, 8);
+        let guard = epoch::pin();
+        let load_factor = || map.load_factor.load(Ordering::Relaxed);
+        for key in 0..2000 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        // Only the first window is complete
+        assert_eq!(load_factor(), 2);
+
+        for key in 0..5000 {
+            map.lookup(&key, &guard);
+        }
+        map.insert(&2000, 2000, &guard).unwrap();
+        assert_eq!(load_factor(), 1);
+    }
+
+    /// Removed values are moved out of the map, so they outlive both the node and the guard.
+    #[test]
+    fn removed_values_outlive_the_guard() {
+        let mut map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        map.insert(&1, String::from("owl"), &guard).unwrap();
+        drop(guard);
+        let value = map.remove(&1).unwrap();
+        assert_eq!(map.remove(&1), None);
+        epoch::pin().flush();
+        assert_eq!(value + "s", "owls");
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn dropped_drain_keeps_the_rest() {
+        let mut map = SplitOrderedList::new();
+        for key in 0..100 {
+            map.insert(&key, key.to_string(), &epoch::pin()).unwrap();
+        }
+        let drained: Vec<_> = map.drain().take(50).collect();
+        assert_eq!(drained.len(), 50);
+        assert!(drained.iter().all(|(key, value)| *value == key.to_string()));
+        assert_eq!(map.len(), 50);
+        let guard = epoch::pin();
+        for key in 0..100 {
+            let drained = drained.iter().any(|(k, _)| *k == key);
+            assert_eq!(map.lookup(&key, &guard).is_some(), !drained);
+        }
+        drop(guard);
+        assert_eq!(map.drain().count(), 50);
+        assert_eq!(map.len(), 0);
+    }
+
+    /// The flag flips once fewer than half the buckets' worth of entries remain.
+    #[test]
+    fn delete_and_check_flags_underloaded_maps() {
+        let mut map = SplitOrderedList::with_load_factor(1);
+        for key in 0..64 {
+            map.insert(&key, key, &epoch::pin()).unwrap();
+        }
+        let size = map.size.load(Ordering::Acquire);
+        assert!(size > 2);
+        for key in 0..64 {
+            let (value, underloaded) = map.delete_and_check(&key).unwrap();
+            assert_eq!(value, key);
+            assert_eq!(underloaded, (63 - key) * 2 < size);
+        }
+        assert_eq!(map.delete_and_check(&0), Err(MapError::KeyNotFound));
+    }
+
+    /// Counts its drops, to tell the values built by `get_or_insert_with` that lost the race.
+    struct DropCounted<'a>(usize, &'a AtomicUsize);
+
+    impl Drop for DropCounted<'_> {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Threads race to fill the same absent key. All of them get the same value back, and the
+    /// values built by the losers are dropped right away.
+    #[test]
+    fn get_or_insert_with_races_keep_one_value() {
+        for key in 0..200 {
+            let map = SplitOrderedList::new();
+            let (calls, drops) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            let barrier = Barrier::new(THREADS);
+            let winners: Vec<usize> = thread::scope(|s| {
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|t| {
+                        let (map, calls, drops, barrier) = (&map, &calls, &drops, &barrier);
+                        s.spawn(move || {
+                            let f = || {
+                                calls.fetch_add(1, Ordering::Relaxed);
+                                DropCounted(t, drops)
+                            };
+                            barrier.wait();
+                            map.get_or_insert_with(&key, f, &epoch::pin()).0
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            assert!(winners.iter().all(|&winner| winner == winners[0]));
+            assert_eq!(map.lookup(&key, &epoch::pin()).unwrap().0, winners[0]);
+            assert_eq!(map.len(), 1);
+            let calls = calls.load(Ordering::Relaxed);
+            assert!((1..=THREADS).contains(&calls));
+            assert_eq!(drops.load(Ordering::Relaxed), calls - 1);
+            drop(map);
+            assert_eq!(drops.load(Ordering::Relaxed), calls);
+        }
+    }
+
+    /// Iterates while other threads insert and delete keys disjoint from the ones that stay put.
+    /// Every stable key is yielded exactly once, and no key more than once.
+    #[test]
+    fn stable_iter_yields_stable_keys_once() {
+        const KEYS: usize = 3000;
+        let map = SplitOrderedList::new();
+        for key in (0..KEYS).filter(|key| key % 3 != 2) {
+            map.insert(&key, key, &epoch::pin()).unwrap();
+        }
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for key in (0..KEYS).filter(|key| key % 3 == 1) {
+                    map.delete(&key, &epoch::pin()).unwrap();
+                }
+                done.fetch_add(1, Ordering::Release);
+            });
+            s.spawn(|| {
+                for key in (0..KEYS).filter(|key| key % 3 == 2) {
+                    map.insert(&key, key, &epoch::pin()).unwrap();
+                }
+                done.fetch_add(1, Ordering::Release);
+            });
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    while done.load(Ordering::Acquire) < 2 {
+                        let guard = epoch::pin();
+                        let mut seen = vec![0; KEYS];
+                        for (key, value) in map.stable_iter(&guard) {
+                            assert_eq!(key, *value);
+                            seen[key] += 1;
+                        }
+                        assert!(seen.iter().all(|&n| n <= 1));
+                        assert!((0..KEYS).step_by(3).all(|key| seen[key] == 1));
+                    }
+                });
+            }
+        });
+    }
+
+    /// Random inserts, deletes and shrinks, checked against a model and validated as they go.
+    #[test]
+    fn random_operations_keep_the_invariants() {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        let map = SplitOrderedList::new();
+        let mut model = std::collections::HashMap::new();
+        let guard = epoch::pin();
+        for i in 0..20_000 {
+            let key = next() % 1024;
+            match next() % 8 {
+                0..=3 => {
+                    let inserted = map.insert(&key, i, &guard).is_ok();
+                    assert_eq!(inserted, !model.contains_key(&key));
+                    model.entry(key).or_insert(i);
+                }
+                4..=6 => assert_eq!(map.delete(&key, &guard).ok().copied(), model.remove(&key)),
+                _ => map.shrink(&guard),
+            }
+            if i % 100 == 0 {
+                map.validate(&guard).unwrap();
+            }
+        }
+        map.validate(&guard).unwrap();
+        assert_eq!(map.len(), model.len());
+        for (key, value) in model {
+            assert_eq!(map.lookup(&key, &guard), Some(&value));
+        }
+    }
+
+    /// Reserving sets `size` to the smallest power of two that fits the entries at the load
+    /// factor, and the inserts that follow don't grow it again.
+    #[test]
+    fn reserve_grows_once_up_front() {
+        const N: usize = 1 << 20;
+        let map = SplitOrderedList::new();
+        map.reserve(N);
+        let size = map.size.load(Ordering::Acquire);
+        assert_eq!(
+            size,
+            (N / SplitOrderedList::<usize>::LOAD_FACTOR + 1).next_power_of_two()
+        );
+
+        let guard = epoch::pin();
+        for key in 0..N {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.size.load(Ordering::Acquire), size);
+        assert_eq!(map.len(), N);
+    }
+
+    #[test]
+    fn to_sorted_vec_by_sorts_on_values() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for &(key, value) in [(1, 30), (2, 10), (3, 20), (4, 30)].iter() {
+            map.insert(&key, value, &guard).unwrap();
+        }
+        // By value, largest first, then by key
+        let top = map.to_sorted_vec_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)), &guard);
+        assert_eq!(top, vec![(1, 30), (4, 30), (3, 20), (2, 10)]);
+        assert_eq!(
+            SplitOrderedList::<usize>::new().to_sorted_vec_by(|a, b| a.cmp(b), &guard),
+            vec![]
+        );
+    }
+
+    /// `max_size_cap(10)` rounds down to 8, so inserts well past `8 * LOAD_FACTOR` stop growing
+    /// `size` there and only lengthen the chains.
+    #[test]
+    fn size_never_exceeds_the_cap() {
+        let map = SplitOrderedList::new();
+        map.max_size_cap(10);
+        let guard = epoch::pin();
+        for key in 0..1000 {
+            map.insert(&key, key, &guard).unwrap();
+            assert!(map.size.load(Ordering::Relaxed) <= 8);
+        }
+        assert_eq!(map.size.load(Ordering::Relaxed), 8);
+        map.reserve(1000);
+        assert_eq!(map.size.load(Ordering::Relaxed), 8);
+        for key in 0..1000 {
+            assert_eq!(map.lookup(&key, &guard), Some(&key));
+        }
+    }
+
+    #[test]
+    fn subset_checks_keys_and_values() {
+        let guard = epoch::pin();
+        let from = |entries: &[(usize, usize)]| {
+            let map = SplitOrderedList::new();
+            for &(key, value) in entries {
+                map.insert(&key, value, &guard).unwrap();
+            }
+            map
+        };
+        let small = from(&[(1, 10), (2, 20)]);
+        let large = from(&[(1, 10), (2, 20), (3, 30)]);
+        assert!(small.is_subset(&large, &guard));
+        assert!(!small.is_superset(&large, &guard));
+        assert!(large.is_superset(&small, &guard));
+        assert!(!large.is_subset(&small, &guard));
+
+        // Equal maps contain each other
+        let same = from(&[(2, 20), (1, 10)]);
+        assert!(small.is_subset(&same, &guard) && small.is_superset(&same, &guard));
+
+        // Same keys, different value
+        let other = from(&[(1, 10), (2, 21)]);
+        assert!(!small.is_subset(&other, &guard));
+
+        let disjoint = from(&[(4, 40), (5, 50)]);
+        assert!(!small.is_subset(&disjoint, &guard));
+        assert!(!disjoint.is_subset(&small, &guard));
+
+        // The empty map is a subset of everything
+        let empty = SplitOrderedList::new();
+        assert!(empty.is_subset(&small, &guard));
+        assert!(small.is_superset(&empty, &guard));
+    }
+
+    /// With a threshold of 100 bytes per bucket, one 300-byte value grows a fresh map while a
+    /// hundred 1-byte values don't, though 100 entries are far past the load factor.
+    #[test]
+    fn weigher_resizes_on_weight_not_count() {
+        let guard = epoch::pin();
+        let heavy = SplitOrderedList::with_weigher(100, |value: &Vec<u8>| value.len());
+        heavy.insert(&0, vec![0; 300], &guard).unwrap();
+        assert_eq!(heavy.size.load(Ordering::Relaxed), 4);
+        heavy.insert(&1, vec![0; 100], &guard).unwrap();
+        assert_eq!(heavy.size.load(Ordering::Relaxed), 4);
+        heavy.insert(&2, vec![0; 1], &guard).unwrap();
+        assert_eq!(heavy.size.load(Ordering::Relaxed), 8);
+        assert_eq!(heavy.weight.load(Ordering::Relaxed), 401);
+        heavy.delete(&0, &guard).unwrap();
+        assert_eq!(heavy.weight.load(Ordering::Relaxed), 101);
+
+        let light = SplitOrderedList::with_weigher(100, |value: &Vec<u8>| value.len());
+        for key in 0..100 {
+            light.insert(&key, vec![0; 1], &guard).unwrap();
+        }
+        assert_eq!(light.size.load(Ordering::Relaxed), 2);
+        assert_eq!(light.len(), 100);
+    }
+
+    #[test]
+    fn len_counts_concurrent_inserts_and_deletes() {
+        let map = SplitOrderedList::new();
+        assert!(map.is_empty());
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let map = &map;
+                s.spawn(move || {
+                    let guard = epoch::pin();
+                    for key in (t..THREADS * ROUNDS).step_by(THREADS) {
+                        map.insert(&key, key, &guard).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), THREADS * ROUNDS);
+
+        // Every thread deletes the even keys among its own
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let map = &map;
+                s.spawn(move || {
+                    let guard = epoch::pin();
+                    for key in (t..THREADS * ROUNDS).step_by(THREADS) {
+                        if key % 2 == 0 {
+                            map.delete(&key, &guard).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), THREADS * ROUNDS / 2);
+        assert!(!map.is_empty());
+    }
+
+    #[test]
+    fn contains_key_tracks_inserts_and_deletes() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        map.insert(&1, 10, &guard).unwrap();
+        map.insert(&2, 20, &guard).unwrap();
+        assert!(map.contains_key(&1, &guard));
+        assert!(!map.contains_key(&3, &guard));
+        map.delete(&2, &guard).unwrap();
+        assert!(!map.contains_key(&2, &guard));
+        assert!(map.contains_key(&1, &guard));
+    }
+
+    /// With a load factor of 1, `size` doubles as soon as `count > size`, twice as early as with
+    /// the default.
+    #[test]
+    fn load_factor_one_resizes_earlier() {
+        let map = SplitOrderedList::with_load_factor(1);
+        let guard = epoch::pin();
+        let mut sizes = Vec::new();
+        for key in 0..17 {
+            map.insert(&key, key, &guard).unwrap();
+            sizes.push(map.size.load(Ordering::Relaxed));
+        }
+        assert_eq!(
+            sizes,
+            [2, 2, 4, 4, 8, 8, 8, 8, 16, 16, 16, 16, 16, 16, 16, 16, 32]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "load factor must be positive")]
+    fn load_factor_zero_panics() {
+        SplitOrderedList::<usize>::with_load_factor(0);
+    }
+
+    /// The mask and the largest valid key follow the width of `usize`, whatever it is.
+    #[test]
+    fn keys_use_the_full_word_width() {
+        let bits = mem::size_of::<usize>() * 8;
+        assert_eq!(SplitOrderedList::<usize>::HI_MASK, 1 << (bits - 1));
+
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        let largest = usize::MAX >> 1;
+        map.insert(&largest, 1, &guard).unwrap();
+        map.insert(&0, 0, &guard).unwrap();
+        assert_eq!(map.lookup(&largest, &guard), Some(&1));
+        // Its data key is all ones, so it sorts last in the whole list
+        let keys: Vec<_> = map.keys(&guard).collect();
+        assert_eq!(keys.last(), Some(&largest));
+        assert_eq!(SplitOrderedList::<usize>::user_key(usize::MAX), largest);
+    }
+
+    #[test]
+    #[should_panic]
+    fn keys_with_the_top_bit_are_rejected() {
+        let map = SplitOrderedList::new();
+        map.insert(&(1 << (mem::size_of::<usize>() * 8 - 1)), 0, &epoch::pin())
+            .unwrap();
+    }
+
+    /// Every entry comes out exactly once, in split order, and no sentinel shows up, even after a
+    /// few resizes initialized many buckets.
+    #[test]
+    fn iter_yields_each_entry_once_in_split_order() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..100 {
+            map.insert(&key, key * 2, &guard).unwrap();
+        }
+        let entries: Vec<_> = map.iter(&guard).collect();
+        assert_eq!(entries.len(), 100);
+        for &(key, value) in entries.iter() {
+            assert_eq!(*value, key * 2);
+        }
+        let split_keys: Vec<_> = entries
+            .iter()
+            .map(|(key, _)| (key | SplitOrderedList::<usize>::HI_MASK).reverse_bits())
+            .collect();
+        assert!(split_keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(SplitOrderedList::<usize>::new().iter(&guard).count(), 0);
+    }
+
+    #[test]
+    fn keys_and_values_project_the_entries() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        let inserted = [0, 7, 8, 1 << 40, 12345];
+        for &key in inserted.iter() {
+            map.insert(&key, key + 1, &guard).unwrap();
+        }
+        let mut keys: Vec<_> = map.keys(&guard).collect();
+        keys.sort_unstable();
+        let mut expected = inserted.to_vec();
+        expected.sort_unstable();
+        assert_eq!(keys, expected);
+
+        let mut values: Vec<_> = map.values(&guard).copied().collect();
+        values.sort_unstable();
+        assert_eq!(
+            values,
+            expected.iter().map(|key| key + 1).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn cleared_maps_can_be_reused() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in 0..1000 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        let segments = map.buckets.segment_count(&guard);
+        map.clear(&guard);
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+        assert_eq!(map.size.load(Ordering::Relaxed), 2);
+        assert_eq!(map.lookup(&5, &guard), None);
+        map.validate(&guard).unwrap();
+
+        // The sentinels left behind are reused as `size` grows again
+        for key in 0..1000 {
+            map.insert(&(key * 3), key, &guard).unwrap();
+        }
+        for key in 0..1000 {
+            assert_eq!(map.lookup(&(key * 3), &guard), Some(&key));
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.buckets.segment_count(&guard), segments);
+        map.validate(&guard).unwrap();
+    }
+
+    #[test]
+    fn get_cloned_outlives_the_guard() {
+        let map = SplitOrderedList::new();
+        let value = {
+            let guard = epoch::pin();
+            map.insert(&1, String::from("one"), &guard).unwrap();
+            assert_eq!(map.get_cloned(&2, &guard), None);
+            map.get_cloned(&1, &guard)
+        };
+        {
+            let guard = epoch::pin();
+            map.delete(&1, &guard).unwrap();
+        }
+        epoch::pin().flush();
+        assert_eq!(value.as_deref(), Some("one"));
+    }
+
+    #[test]
+    fn lookups_never_initialize_buckets() {
+        let map = SplitOrderedList::<usize>::new();
+        let guard = epoch::pin();
+        assert_eq!(map.lookup(&1, &guard), None);
+        assert!(!map.contains_key(&2, &guard));
+        assert!(matches!(map.probe(&3, &guard), Probe::BucketUninitialized));
+        assert_eq!(map.buckets.segment_count(&guard), 0);
+        assert!(sentinels(&map, &guard).is_empty());
+
+        // Bucket 1 stays uninitialized once bucket 0 exists
+        map.insert(&0, 0, &guard).unwrap();
+        let before = sentinels(&map, &guard);
+        assert_eq!(map.lookup(&1, &guard), None);
+        assert!(matches!(map.probe(&3, &guard), Probe::BucketUninitialized));
+        assert_eq!(sentinels(&map, &guard), before);
+        assert!(map.buckets.load(1, &guard).is_null());
+    }
+
+    /// With a `size` of 2^21, the first insert into bucket 2^20 - 1 initializes its 20 ancestors
+    /// down from the root. A small stack is enough for the walk.
+    #[test]
+    fn deep_ancestor_chains_are_initialized() {
+        let map = SplitOrderedList::with_capacity(1 << 21);
+        assert_eq!(map.size.load(Ordering::Relaxed), 1 << 21);
+        let key = (1 << 20) - 1;
+        thread::scope(|s| {
+            thread::Builder::new()
+                .stack_size(64 * 1024)
+                .spawn_scoped(s, || map.insert(&key, key, &epoch::pin()).unwrap())
+                .unwrap();
+        });
+
+        let guard = epoch::pin();
+        let mut initialized = Vec::new();
+        map.buckets
+            .for_each(|index, _| initialized.push(index), &guard);
+        let ancestors: Vec<_> = (0..=20).map(|bits| (1 << bits) - 1).collect();
+        assert_eq!(initialized, ancestors);
+        assert_eq!(map.lookup(&key, &guard), Some(&key));
+        map.validate(&guard).unwrap();
+    }
+
+    #[test]
+    fn entries_insert_only_when_vacant() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        let mut calls = 0;
+        assert_eq!(
+            *map.entry(1, &guard).or_insert_with(|| {
+                calls += 1;
+                10
+            }),
+            10
+        );
+        assert_eq!(
+            *map.entry(1, &guard).or_insert_with(|| {
+                calls += 1;
+                11
+            }),
+            10
+        );
+        assert_eq!(calls, 1);
+        assert_eq!(*map.entry(2, &guard).or_insert(20), 20);
+
+        match map.entry(1, &guard) {
+            Entry::Occupied(entry) => {
+                assert_eq!(entry.key(), 1);
+                assert_eq!(*entry.get(), 10);
+                assert_eq!(*entry.into_value(), 10);
+            }
+            Entry::Vacant(_) => panic!("key 1 is present"),
+        }
+
+        // A vacant entry that another insert beat returns the value already there
+        let entry = map.entry(3, &guard);
+        assert_eq!(entry.key(), 3);
+        map.insert(&3, 30, &guard).unwrap();
+        match entry {
+            Entry::Vacant(entry) => assert_eq!(*entry.insert(31), 30),
+            Entry::Occupied(_) => panic!("key 3 was absent"),
+        }
+        assert_eq!(map.len(), 3);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_round_trip() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in [0, 3, 1 << 40].iter() {
+            map.insert(key, key + 1, &guard).unwrap();
+        }
+        let json = serde_json::to_string(&map).unwrap();
+        let back: SplitOrderedList<usize> = serde_json::from_str(&json).unwrap();
+        let entries = back.to_sorted_vec_by(|a, b| a.cmp(b), &guard);
+        assert_eq!(entries, [(0, 1), (3, 4), (1 << 40, (1 << 40) + 1)]);
+        assert_eq!(back.len(), 3);
+
+        // Later duplicates overwrite earlier ones
+        let back: SplitOrderedList<usize> = serde_json::from_str(r#"{"1": 10, "1": 11}"#).unwrap();
+        assert_eq!(back.lookup(&1, &guard), Some(&11));
+        assert_eq!(back.len(), 1);
+
+        let invalid = format!(r#"{{"{}": 0}}"#, SplitOrderedList::<usize>::HI_MASK);
+        assert!(serde_json::from_str::<SplitOrderedList<usize>>(&invalid).is_err());
+    }
+
+    #[test]
+    fn with_capacity_never_grows_while_filling() {
+        for &n in [0, 1, 100, 1000, 4096].iter() {
+            let map = SplitOrderedList::with_capacity(n);
+            let size = map.size.load(Ordering::Relaxed);
+            assert!(size.is_power_of_two() && size >= 2);
+            assert!(size * SplitOrderedList::<usize>::LOAD_FACTOR >= n);
+            let guard = epoch::pin();
+            for key in 0..n {
+                map.insert(&key, key, &guard).unwrap();
+            }
+            assert_eq!(map.size.load(Ordering::Relaxed), size, "n = {}", n);
+            for key in 0..n {
+                assert_eq!(map.lookup(&key, &guard), Some(&key));
+            }
+            map.validate(&guard).unwrap();
+        }
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let map = SplitOrderedList::with_load_factor(3);
+        let guard = epoch::pin();
+        for key in 0..50 {
+            map.insert(&key, key.to_string(), &guard).unwrap();
+        }
+        let copy = map.clone();
+        map.delete(&1, &guard).unwrap();
+        map.insert(&100, String::from("new"), &guard).unwrap();
+        map.update(&2, |_| String::from("changed"), &guard).unwrap();
+
+        assert_eq!(copy.len(), 50);
+        assert_eq!(copy.lookup(&1, &guard).map(String::as_str), Some("1"));
+        assert_eq!(copy.lookup(&2, &guard).map(String::as_str), Some("2"));
+        assert_eq!(copy.lookup(&100, &guard), None);
+        assert_eq!(copy.load_factor.load(Ordering::Relaxed), 3);
+        copy.validate(&guard).unwrap();
+    }
+
+    #[test]
+    fn debug_prints_user_keys() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        map.insert(&1, "one", &guard).unwrap();
+        assert_eq!(format!("{:?}", map), r#"{1: "one"}"#);
+        let split_key = (1 | SplitOrderedList::<&str>::HI_MASK).reverse_bits();
+        assert!(!format!("{:?}", map).contains(&split_key.to_string()));
+
+        let pretty = format!("{:#?}", map);
+        assert!(pretty.starts_with("SplitOrderedList {"));
+        assert!(pretty.contains("size: 2"));
+        assert!(pretty.contains("count: 1"));
+        assert!(pretty.contains(r#"1: "one""#));
+        assert_eq!(format!("{:?}", SplitOrderedList::<usize>::new()), "{}");
+    }
+
+    #[test]
+    fn lookup_or_falls_back_to_the_default() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        map.insert(&1, 10, &guard).unwrap();
+        let default = 0;
+        assert!(ptr::eq(
+            map.lookup_or(&1, &default, &guard),
+            map.lookup(&1, &guard).unwrap()
+        ));
+        assert!(ptr::eq(map.lookup_or(&2, &default, &guard), &default));
+    }
+
+    #[test]
+    fn insert_many_counts_new_keys_only() {
+        const N: usize = 10_000;
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(map.insert_many((0..N).map(|key| (key, key)), &guard), N);
+        // Doubling kept up with the batch
+        assert_eq!(
+            map.size.load(Ordering::Relaxed),
+            (N / SplitOrderedList::<usize>::LOAD_FACTOR).next_power_of_two()
+        );
+        for key in 0..N {
+            assert_eq!(map.lookup(&key, &guard), Some(&key));
+        }
+
+        // Half of the next batch is already present and keeps its old value
+        let batch = (N / 2..N + N / 2).map(|key| (key, key + 1));
+        assert_eq!(map.insert_many(batch, &guard), N / 2);
+        assert_eq!(map.lookup(&(N - 1), &guard), Some(&(N - 1)));
+        assert_eq!(map.lookup(&N, &guard), Some(&(N + 1)));
+        assert_eq!(map.len(), N + N / 2);
+    }
+
+    #[test]
+    fn errors_name_the_reason() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        map.insert(&1, String::from("a"), &guard).unwrap();
+        match map.insert(&1, String::from("b"), &guard) {
+            Err(MapError::KeyExists(value)) => assert_eq!(value, "b"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(map.delete(&2, &guard), Err(MapError::KeyNotFound));
+        assert_eq!(map.delete(&1, &guard).map(String::as_str), Ok("a"));
+        assert_eq!(map.delete(&1, &guard), Err(MapError::KeyNotFound));
+
+        assert_eq!(MapError::KeyExists(3).into_value(), Some(3));
+        assert_eq!(MapError::<usize>::KeyNotFound.into_value(), None);
+    }
+
+    #[test]
+    fn scan_yields_exactly_the_keys_in_range() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for &key in [40, 3, 17, 10, 20, 9, 11, 0].iter() {
+            map.insert(&key, key * 10, &guard).unwrap();
+        }
+        let mut keys: Vec<_> = map
+            .scan(10, 20, &guard)
+            .map(|(key, value)| {
+                assert_eq!(*value, key * 10);
+                key
+            })
+            .collect();
+        keys.sort_unstable();
+        assert_eq!(keys, [10, 11, 17]);
+        assert_eq!(map.scan(5, 5, &guard).count(), 0);
+        assert_eq!(map.scan(0, usize::MAX, &guard).count(), 8);
+    }
+
+    /// Of 6, 4, 12 and 1, key 4 has the smallest bit reversal, though 1 is the smallest key.
+    #[test]
+    fn first_is_the_split_order_minimum() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(map.first(&guard), None);
+        for &key in [6, 4, 12, 1].iter() {
+            map.insert(&key, key * 10, &guard).unwrap();
+        }
+        assert_eq!(map.first(&guard), Some((4, &40)));
+        // Reversed, the single bit of 8 lands below the one of 4
+        map.insert(&8, 80, &guard).unwrap();
+        assert_eq!(map.first(&guard), Some((8, &80)));
+        map.delete(&8, &guard).unwrap();
+        assert_eq!(map.first(&guard), Some((4, &40)));
+    }
+
+    #[test]
+    fn load_factor_reports_entries_per_bucket() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert_eq!(map.load_factor(), 0.0);
+        for key in 0..3 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert!((map.load_factor() - 1.5).abs() < f64::EPSILON);
+        // The 5th insert doubles `size` to 4
+        for key in 3..5 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.size.load(Ordering::Relaxed), 4);
+        assert!((map.load_factor() - 1.25).abs() < f64::EPSILON);
+    }
+
+    /// Each rejected value comes back in the error and is dropped exactly once with it, and the
+    /// value in the map only once the map is dropped.
+    #[test]
+    fn duplicate_inserts_drop_each_value_once() {
+        const ROUNDS: usize = 10_000;
+        let drops = AtomicUsize::new(0);
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert!(map.insert(&1, DropCounted(0, &drops), &guard).is_ok());
+        for round in 1..=ROUNDS {
+            match map.insert(&1, DropCounted(round, &drops), &guard) {
+                Err(MapError::KeyExists(value)) => assert_eq!(value.0, round),
+                _ => panic!("key 1 is present"),
+            }
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), ROUNDS);
+        assert_eq!(map.lookup(&1, &guard).map(|value| value.0), Some(0));
+        assert_eq!(map.len(), 1);
+        drop(guard);
+        drop(map);
+        assert_eq!(drops.load(Ordering::Relaxed), ROUNDS + 1);
+    }
+
+    #[test]
+    fn get_arc_shares_the_stored_value() {
+        let map = SplitOrderedList::new();
+        let arc = {
+            let guard = epoch::pin();
+            map.insert(&1, Arc::new(String::from("shared")), &guard)
+                .unwrap();
+            assert!(map.get_arc(&2, &guard).is_none());
+            map.get_arc(&1, &guard).unwrap()
+        };
+        let guard = epoch::pin();
+        assert!(Arc::ptr_eq(&arc, map.lookup(&1, &guard).unwrap()));
+        assert_eq!(Arc::strong_count(&arc), 2);
+        drop(guard);
+        drop(map);
+        assert_eq!(Arc::strong_count(&arc), 1);
+        assert_eq!(*arc, "shared");
+    }
+
+    #[test]
+    fn max_chain_len_reflects_clustered_keys() {
+        let map = SplitOrderedList::with_capacity(256);
+        let size = map.size.load(Ordering::Relaxed);
+        let guard = epoch::pin();
+        assert_eq!(map.max_chain_len(&guard), 0);
+        for key in 1..11 {
+            map.insert(&key, key, &guard).unwrap();
+        }
+        assert_eq!(map.max_chain_len(&guard), 1);
+
+        // All of these land in bucket 3
+        for i in 0..20 {
+            let key = 3 + i * size;
+            let _ = map.insert(&key, key, &guard);
+        }
+        assert_eq!(map.size.load(Ordering::Relaxed), size);
+        assert_eq!(map.max_chain_len(&guard), 20);
+        map.delete(&3, &guard).unwrap();
+        assert_eq!(map.max_chain_len(&guard), 19);
+    }
+
+    #[test]
+    fn estimate_len_agrees_with_exact_len_after_join() {
+        let map = SplitOrderedList::new();
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let map = &map;
+                s.spawn(move || {
+                    let guard = epoch::pin();
+                    for i in 0..ROUNDS {
+                        let key = t * ROUNDS + i;
+                        map.insert(&key, key, &guard).unwrap();
+                        if i % 3 == 0 {
+                            map.delete(&key, &guard).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let guard = epoch::pin();
+        assert_eq!(map.estimate_len(), map.exact_len(&guard));
+        assert_eq!(
+            map.exact_len(&guard),
+            THREADS * (ROUNDS - ROUNDS.div_ceil(3))
+        );
+    }
+
+    #[test]
+    fn probe_tells_cold_buckets_from_misses() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        assert!(matches!(map.probe(&0, &guard), Probe::BucketUninitialized));
+        map.insert(&0, 10, &guard).unwrap();
+        assert!(matches!(map.probe(&0, &guard), Probe::Found(&10)));
+        // Same bucket as 0
+        assert!(matches!(map.probe(&2, &guard), Probe::Absent));
+        // Bucket 1 has no sentinel yet
+        assert!(matches!(map.probe(&1, &guard), Probe::BucketUninitialized));
+        map.insert(&3, 30, &guard).unwrap();
+        assert!(matches!(map.probe(&1, &guard), Probe::Absent));
+        map.delete(&0, &guard).unwrap();
+        assert!(matches!(map.probe(&0, &guard), Probe::Absent));
+    }
+
+    #[test]
+    fn get_many_keeps_the_positions() {
+        let map = SplitOrderedList::new();
+        let guard = epoch::pin();
+        for key in [2, 5, 9].iter() {
+            map.insert(key, key * 10, &guard).unwrap();
         }
+        let [a, b, c, d, e] = map.get_many(&[9, 1, 2, 9, 100], &guard);
+        assert_eq!(
+            [a, b, c, d, e],
+            [Some(&90), None, Some(&20), Some(&90), None]
+        );
+        // A repeated key yields the same reference
+        assert!(ptr::eq(a.unwrap(), d.unwrap()));
+        assert_eq!(map.get_many(&[], &guard), []);
     }
 }
@@ -0,0 +1,131 @@
+//! Split-ordered list with expiring entries.
+
+use crossbeam_epoch::Guard;
+use std::time::{Duration, Instant};
+
+use super::split_ordered_list::SplitOrderedList;
+
+/// `SplitOrderedList` whose entries expire after a per-entry time-to-live.
+///
+/// Expiry is lazy: an expired entry reads as absent, but it stays in the list (and keeps counting
+/// as an item) until `evict_expired` sweeps it or an insert for the same key replaces it.
+/// Deadlines are measured with `std::time::Instant`, i.e. the monotonic clock. A time-to-live too
+/// long for an `Instant` to represent never expires.
+#[derive(Debug)]
+pub struct TtlMap<V> {
+    /// Values with their deadline, `None` if it lies beyond what `Instant` can hold
+    inner: SplitOrderedList<(V, Option<Instant>)>,
+}
+
+impl<V> Default for TtlMap<V> {
+    fn default() -> Self {
+        Self {
+            inner: SplitOrderedList::new(),
+        }
+    }
+}
+
+impl<V> TtlMap<V> {
+    /// Creates a new map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_expired(deadline: &Option<Instant>) -> bool {
+        deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Inserts `value` until `ttl` has elapsed. An expired entry for the same key is replaced,
+    /// while a live one makes the insert fail.
+    pub fn insert_with_ttl(
+        &self,
+        key: &usize,
+        value: V,
+        ttl: Duration,
+        guard: &Guard,
+    ) -> Result<(), V> {
+        let mut entry = (value, Instant::now().checked_add(ttl));
+        loop {
+            match self.inner.insert(key, entry, guard) {
+                Ok(()) => return Ok(()),
+                Err(err) => entry = err.into_value().unwrap(),
+            }
+            if self
+                .inner
+                .delete_if(key, |(_, deadline)| Self::is_expired(deadline), guard)
+                .is_err()
+                && self.lookup(key, guard).is_some()
+            {
+                return Err(entry.0);
+            }
+        }
+    }
+
+    /// Returns the value for `key`, or `None` if it is absent or expired.
+    pub fn lookup<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        match self.inner.lookup(key, guard) {
+            Some((value, deadline)) if !Self::is_expired(deadline) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Deletes the entry for `key`, expired or not, and returns its value. Returns `None` if `key`
+    /// is absent.
+    pub fn delete<'a>(&'a self, key: &usize, guard: &'a Guard) -> Option<&'a V> {
+        self.inner.delete(key, guard).ok().map(|(value, _)| value)
+    }
+
+    /// Deletes every expired entry. Returns the number of entries deleted.
+    pub fn evict_expired(&self, guard: &Guard) -> usize {
+        let mut evicted = 0;
+        self.inner.for_each(
+            |key, (_, deadline)| {
+                if Self::is_expired(deadline)
+                    && self
+                        .inner
+                        .delete_if(&key, |(_, deadline)| Self::is_expired(deadline), guard)
+                        .is_ok()
+                {
+                    evicted += 1;
+                }
+            },
+            guard,
+        );
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_epoch as epoch;
+
+    #[test]
+    fn expired_entries_read_as_absent_and_are_replaced() {
+        let map = TtlMap::new();
+        let guard = epoch::pin();
+        map.insert_with_ttl(&1, "old", Duration::from_secs(0), &guard)
+            .unwrap();
+        assert_eq!(map.lookup(&1, &guard), None);
+        map.insert_with_ttl(&1, "new", Duration::from_secs(60), &guard)
+            .unwrap();
+        assert_eq!(map.lookup(&1, &guard), Some(&"new"));
+        assert_eq!(
+            map.insert_with_ttl(&1, "newer", Duration::from_secs(60), &guard),
+            Err("newer")
+        );
+    }
+
+    #[test]
+    fn unrepresentable_deadlines_never_expire() {
+        let map = TtlMap::new();
+        let guard = epoch::pin();
+        map.insert_with_ttl(&1, 1, Duration::MAX, &guard).unwrap();
+        map.insert_with_ttl(&2, 2, Duration::from_secs(0), &guard)
+            .unwrap();
+        assert_eq!(map.evict_expired(&guard), 1);
+        assert_eq!(map.lookup(&1, &guard), Some(&1));
+        assert_eq!(map.delete(&1, &guard), Some(&1));
+        assert_eq!(map.delete(&1, &guard), None);
+    }
+}